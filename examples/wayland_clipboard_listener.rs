@@ -1,19 +1,13 @@
-use clip_bridge::{
-    ClipboardType,
-    wayland::{GlobalData, WaylandState},
-};
+use clip_bridge::wayland::{GlobalData, WaylandState};
 use tokio::sync::mpsc;
-use tracing_subscriber;
 use wayland_client::Connection;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
-    // Create channels for sync events and clipboard set requests
+    // Create channel for sync events
     let (sync_tx, mut sync_rx) = mpsc::unbounded_channel();
-    let (set_clipboard_tx, _set_clipboard_rx) =
-        mpsc::unbounded_channel::<(String, ClipboardType)>();
 
     // Connect to Wayland server
     let wayland_conn = Connection::connect_to_env()?;
@@ -22,7 +16,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let qh = event_queue.handle();
 
     // Create WaylandState
-    let mut wayland_state = WaylandState::new(qh.clone(), sync_tx, set_clipboard_tx);
+    let mut wayland_state = WaylandState::new(qh.clone(), sync_tx);
 
     // Acquire global data
     display.get_registry(&qh, GlobalData);
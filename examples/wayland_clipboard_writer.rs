@@ -1,4 +1,4 @@
-use clip_bridge::{ClipboardType, wayland::WaylandState};
+use clip_bridge::{ClipboardContent, ClipboardType, wayland::WaylandState};
 use tokio::sync::mpsc;
 use tracing::info;
 use wayland_client::Connection;
@@ -10,15 +10,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init();
 
     let (sync_tx, _sync_rx) = mpsc::unbounded_channel();
-    let (set_clipboard_tx, _set_clipboard_rx) =
-        mpsc::unbounded_channel::<(String, ClipboardType)>();
 
     let wayland_conn = Connection::connect_to_env()?;
     let display = wayland_conn.display();
     let mut event_queue = wayland_conn.new_event_queue();
     let qh = event_queue.handle();
 
-    let mut wayland_state = WaylandState::new(qh.clone(), sync_tx, set_clipboard_tx);
+    let mut wayland_state = WaylandState::new(qh.clone(), sync_tx);
 
     display.get_registry(&qh, clip_bridge::wayland::GlobalData);
 
@@ -28,7 +26,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Run the rest in spawn_blocking to have a tokio runtime
     tokio::task::spawn_blocking(move || {
-        wayland_state.set_clipboard_content("Hello, World!".to_string(), ClipboardType::Clipboard);
+        wayland_state.set_clipboard_content(
+            ClipboardContent::text("Hello, World!"),
+            ClipboardType::Clipboard,
+        );
 
         info!("Before second roundtrip");
         event_queue.roundtrip(&mut wayland_state).unwrap();
@@ -1,6 +1,5 @@
 use clip_bridge::x11::X11State;
 use tokio::sync::mpsc::unbounded_channel;
-use tracing_subscriber;
 use x11rb::connect;
 
 #[tokio::main]
@@ -0,0 +1,319 @@
+//! Common interface of the clipboards the bridge can sync.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::{ClipboardContent, ClipboardType, Error, is_text_mime};
+
+// ============================================================================
+// Backend Trait
+// ============================================================================
+
+/// What a backend is able to exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    /// Selections the backend can read and write.
+    pub selections: Vec<ClipboardType>,
+    /// MIME types the backend can read and write, or `None` if it takes any.
+    pub mime_types: Option<Vec<String>>,
+}
+
+impl BackendCapabilities {
+    pub fn supports_selection(&self, clipboard_type: &ClipboardType) -> bool {
+        self.selections.contains(clipboard_type)
+    }
+
+    /// Whether content can be written as `mime_type`, treating all text
+    /// aliases as the same format.
+    pub fn supports_mime_type(&self, mime_type: &str) -> bool {
+        let Some(mime_types) = &self.mime_types else {
+            return true;
+        };
+        mime_types
+            .iter()
+            .any(|m| m == mime_type || (is_text_mime(m) && is_text_mime(mime_type)))
+    }
+}
+
+/// Stream of the selection changes made by other clients of a backend.
+pub struct ChangeStream {
+    changes: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    /// Where the backend keeps what [`ClipboardBackend::read`] returns.
+    contents: Option<Arc<Mutex<SelectionContents>>>,
+}
+
+impl ChangeStream {
+    /// Receives the next change, or `None` once the backend stopped.
+    pub async fn recv(&mut self) -> Option<(ClipboardContent, ClipboardType)> {
+        let (content, clipboard_type) = self.changes.recv().await?;
+        if let Some(contents) = &self.contents {
+            *contents.lock().unwrap().get_mut(&clipboard_type) = Some(content.clone());
+        }
+        Some((content, clipboard_type))
+    }
+}
+
+impl From<mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>> for ChangeStream {
+    fn from(changes: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>) -> Self {
+        Self {
+            changes,
+            contents: None,
+        }
+    }
+}
+
+/// A clipboard that can be bridged to another one.
+///
+/// Backends run their own event loop; these methods only talk to it, so they
+/// never block on a selection owner.
+pub trait ClipboardBackend: Send + 'static {
+    /// Name used in logs.
+    fn name(&self) -> &str;
+
+    fn capabilities(&self) -> BackendCapabilities;
+
+    /// Returns the last content seen on or written to `clipboard_type`.
+    fn read(&self, clipboard_type: &ClipboardType) -> Option<ClipboardContent>;
+
+    /// Takes ownership of `clipboard_type` with `content`.
+    fn write(&self, content: ClipboardContent, clipboard_type: ClipboardType) -> Result<(), Error>;
+
+    /// Takes the stream of selection changes. Returns `None` if it was already taken.
+    ///
+    /// Selections set through [`write`](Self::write) must not be reported
+    /// back. Backends recognize them by who set them rather than by content,
+    /// e.g. by owner window or by the [origin](crate::origin_mime_type)
+    /// format they offer, so copying the same content again is still a change.
+    fn changes(&mut self) -> Option<ChangeStream>;
+}
+
+// ============================================================================
+// Channel Backend
+// ============================================================================
+
+/// The channel ends handed to the event loop of a [`ChannelBackend`].
+///
+/// The event loop reports clipboard changes through `sync_tx` and receives the
+/// content it must take ownership of from `set_clipboard_rx`.
+pub struct SyncEndpoint {
+    pub sync_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    pub set_clipboard_rx: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+}
+
+/// A backend whose event loop runs elsewhere and is driven through channels,
+/// as done for [`X11State`](crate::x11::X11State) and
+/// [`WaylandState`](crate::wayland::WaylandState).
+pub struct ChannelBackend {
+    name: String,
+    capabilities: BackendCapabilities,
+    changes: Option<ChangeStream>,
+    set_clipboard_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    contents: Arc<Mutex<SelectionContents>>,
+}
+
+/// Last content of each selection.
+#[derive(Debug, Default)]
+struct SelectionContents {
+    clipboard: Option<ClipboardContent>,
+    primary: Option<ClipboardContent>,
+}
+
+impl SelectionContents {
+    fn get_mut(&mut self, clipboard_type: &ClipboardType) -> &mut Option<ClipboardContent> {
+        match clipboard_type {
+            ClipboardType::Clipboard => &mut self.clipboard,
+            ClipboardType::Primary => &mut self.primary,
+        }
+    }
+}
+
+impl ChannelBackend {
+    /// Creates the backend along with the endpoint its event loop must use.
+    pub fn new(name: impl Into<String>, capabilities: BackendCapabilities) -> (Self, SyncEndpoint) {
+        let (sync_tx, changes) = mpsc::unbounded_channel();
+        let (set_clipboard_tx, set_clipboard_rx) = mpsc::unbounded_channel();
+
+        let contents = Arc::new(Mutex::new(SelectionContents::default()));
+
+        let backend = Self {
+            name: name.into(),
+            capabilities,
+            changes: Some(ChangeStream {
+                changes,
+                contents: Some(contents.clone()),
+            }),
+            set_clipboard_tx,
+            contents,
+        };
+        let endpoint = SyncEndpoint {
+            sync_tx,
+            set_clipboard_rx,
+        };
+        (backend, endpoint)
+    }
+}
+
+impl ClipboardBackend for ChannelBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        self.capabilities.clone()
+    }
+
+    fn read(&self, clipboard_type: &ClipboardType) -> Option<ClipboardContent> {
+        self.contents
+            .lock()
+            .unwrap()
+            .get_mut(clipboard_type)
+            .clone()
+    }
+
+    fn write(&self, content: ClipboardContent, clipboard_type: ClipboardType) -> Result<(), Error> {
+        *self.contents.lock().unwrap().get_mut(&clipboard_type) = Some(content.clone());
+        self.set_clipboard_tx
+            .send((content, clipboard_type))
+            .map_err(|_| Error::ConnectionLost(format!("{} event loop stopped", self.name)))
+    }
+
+    fn changes(&mut self) -> Option<ChangeStream> {
+        self.changes.take()
+    }
+}
+
+// ============================================================================
+// Memory Backend
+// ============================================================================
+
+/// A backend holding its selections in memory, for tests and embedding.
+///
+/// Other clients are simulated through the [`MemoryClipboard`] returned by
+/// [`MemoryBackend::new`].
+pub struct MemoryBackend {
+    name: String,
+    capabilities: BackendCapabilities,
+    changes: Option<ChangeStream>,
+    writes_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    contents: Arc<Mutex<SelectionContents>>,
+}
+
+/// The other clients of a [`MemoryBackend`].
+pub struct MemoryClipboard {
+    change_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    writes: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    contents: Arc<Mutex<SelectionContents>>,
+}
+
+impl MemoryBackend {
+    pub fn new(
+        name: impl Into<String>,
+        capabilities: BackendCapabilities,
+    ) -> (Self, MemoryClipboard) {
+        let (change_tx, changes) = mpsc::unbounded_channel();
+        let (writes_tx, writes) = mpsc::unbounded_channel();
+        let contents = Arc::new(Mutex::new(SelectionContents::default()));
+
+        let backend = Self {
+            name: name.into(),
+            capabilities,
+            changes: Some(changes.into()),
+            writes_tx,
+            contents: contents.clone(),
+        };
+        let clipboard = MemoryClipboard {
+            change_tx,
+            writes,
+            contents,
+        };
+        (backend, clipboard)
+    }
+}
+
+impl ClipboardBackend for MemoryBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        self.capabilities.clone()
+    }
+
+    fn read(&self, clipboard_type: &ClipboardType) -> Option<ClipboardContent> {
+        self.contents
+            .lock()
+            .unwrap()
+            .get_mut(clipboard_type)
+            .clone()
+    }
+
+    fn write(&self, content: ClipboardContent, clipboard_type: ClipboardType) -> Result<(), Error> {
+        *self.contents.lock().unwrap().get_mut(&clipboard_type) = Some(content.clone());
+        self.writes_tx
+            .send((content, clipboard_type))
+            .map_err(|_| Error::ConnectionLost(format!("{} clipboard dropped", self.name)))
+    }
+
+    fn changes(&mut self) -> Option<ChangeStream> {
+        self.changes.take()
+    }
+}
+
+impl MemoryClipboard {
+    /// Sets `clipboard_type` as another client would, reporting the change.
+    pub fn copy(&self, content: ClipboardContent, clipboard_type: ClipboardType) {
+        *self.contents.lock().unwrap().get_mut(&clipboard_type) = Some(content.clone());
+        let _ = self.change_tx.send((content, clipboard_type));
+    }
+
+    /// Returns the current content of `clipboard_type`.
+    pub fn paste(&self, clipboard_type: &ClipboardType) -> Option<ClipboardContent> {
+        self.contents
+            .lock()
+            .unwrap()
+            .get_mut(clipboard_type)
+            .clone()
+    }
+
+    /// Waits for the next write made through the backend.
+    pub async fn next_write(&mut self) -> Option<(ClipboardContent, ClipboardType)> {
+        self.writes.recv().await
+    }
+
+    /// Returns the next write made through the backend, if one is queued.
+    pub fn try_next_write(&mut self) -> Option<(ClipboardContent, ClipboardType)> {
+        self.writes.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_returns_the_last_change_or_write() {
+        let capabilities = BackendCapabilities {
+            selections: vec![ClipboardType::Clipboard, ClipboardType::Primary],
+            mime_types: None,
+        };
+        let (mut backend, mut endpoint) = ChannelBackend::new("Channel", capabilities);
+        let mut changes = backend.changes().unwrap();
+        assert_eq!(backend.read(&ClipboardType::Clipboard), None);
+
+        let copied = ClipboardContent::text("copied");
+        endpoint
+            .sync_tx
+            .send((copied.clone(), ClipboardType::Clipboard))
+            .unwrap();
+        changes.recv().await.unwrap();
+        assert_eq!(backend.read(&ClipboardType::Clipboard), Some(copied));
+
+        let written = ClipboardContent::text("written");
+        backend
+            .write(written.clone(), ClipboardType::Primary)
+            .unwrap();
+        assert!(endpoint.set_clipboard_rx.recv().await.is_some());
+        assert_eq!(backend.read(&ClipboardType::Primary), Some(written));
+    }
+}
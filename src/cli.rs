@@ -0,0 +1,267 @@
+//! Command-line arguments of the `clip-bridge` binary.
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clip_bridge::{ClipboardType, TEXT_PLAIN_UTF8_ATOM, config::Config, sync::Direction};
+use tracing::level_filters::LevelFilter;
+
+// ============================================================================
+// Arguments
+// ============================================================================
+
+/// Sync your X11 and Wayland clipboard seamlessly
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// X11 display to connect to, instead of $DISPLAY
+    #[arg(long, global = true, value_name = "DISPLAY")]
+    pub display: Option<String>,
+
+    /// Wayland display to connect to, instead of $WAYLAND_DISPLAY
+    #[arg(long, global = true, value_name = "NAME")]
+    pub wayland_display: Option<String>,
+
+    /// Configuration file [default: $XDG_CONFIG_HOME/clip-bridge/config.toml]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Most verbose log level written to stderr (off, error, warn, info, debug
+    /// or trace) [default: info for run, warn otherwise]
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// What to do, running the bridge if omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the bridge, syncing selections until stopped
+    Run(RunArgs),
+    /// Copy standard input to a selection, serving it until interrupted
+    Copy(CopyArgs),
+    /// Write the content of a selection to standard output
+    Paste(PasteArgs),
+}
+
+/// Options of `run`, overriding the configuration file.
+#[derive(Debug, Default, Args)]
+pub struct RunArgs {
+    /// Selections to sync, turning the others off. Selections the
+    /// configuration file turns off stay off [default: clipboard,primary]
+    #[arg(long, value_delimiter = ',', value_name = "SELECTIONS")]
+    pub selections: Option<Vec<Selection>>,
+
+    /// Which way the synced selections are synced, leaving those turned off
+    /// alone [default: both]
+    #[arg(long, value_enum)]
+    pub direction: Option<SyncDirection>,
+
+    /// Which way the clipboard is synced, taking precedence over the above
+    /// and the configuration file
+    #[arg(long, value_enum, value_name = "DIRECTION")]
+    pub clipboard: Option<SyncDirection>,
+
+    /// Which way the primary selection is synced, taking precedence over the
+    /// above and the configuration file
+    #[arg(long, value_enum, value_name = "DIRECTION")]
+    pub primary: Option<SyncDirection>,
+}
+
+impl RunArgs {
+    /// Replaces the settings of `config` given on the command line.
+    pub fn apply(&self, config: &mut Config) {
+        for (selection, only) in [
+            (Selection::Clipboard, self.clipboard),
+            (Selection::Primary, self.primary),
+        ] {
+            let direction = config.sync.direction_mut(&selection.into());
+            // Listed selections keep the direction of the configuration file
+            if let Some(selections) = &self.selections
+                && !selections.contains(&selection)
+            {
+                *direction = Direction::Off;
+            }
+            if let Some(way) = self.direction
+                && *direction != Direction::Off
+            {
+                *direction = way.into();
+            }
+            if let Some(way) = only {
+                *direction = way.into();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct CopyArgs {
+    /// Side whose selection is set
+    #[arg(long, value_enum)]
+    pub to: Side,
+
+    /// Selection to set
+    #[arg(long, value_enum, default_value_t = Selection::Clipboard)]
+    pub selection: Selection,
+
+    /// MIME type of standard input
+    #[arg(long, default_value = TEXT_PLAIN_UTF8_ATOM, value_name = "TYPE")]
+    pub mime_type: String,
+}
+
+#[derive(Debug, Args)]
+pub struct PasteArgs {
+    /// Side whose selection is read
+    #[arg(long, value_enum)]
+    pub from: Side,
+
+    /// Selection to read
+    #[arg(long, value_enum, default_value_t = Selection::Clipboard)]
+    pub selection: Selection,
+
+    /// MIME type to write
+    #[arg(long, default_value = TEXT_PLAIN_UTF8_ATOM, value_name = "TYPE")]
+    pub mime_type: String,
+
+    /// List the MIME types that were read instead: text and the synced
+    /// formats the selection is offered as, not every type the owner offers
+    #[arg(long, conflicts_with = "mime_type")]
+    pub list_types: bool,
+}
+
+// ============================================================================
+// Values
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Side {
+    X11,
+    Wayland,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Selection {
+    Clipboard,
+    Primary,
+}
+
+impl From<Selection> for ClipboardType {
+    fn from(selection: Selection) -> Self {
+        match selection {
+            Selection::Clipboard => ClipboardType::Clipboard,
+            Selection::Primary => ClipboardType::Primary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SyncDirection {
+    #[default]
+    Both,
+    #[value(name = "x11-to-wayland")]
+    X11ToWayland,
+    #[value(name = "wayland-to-x11")]
+    WaylandToX11,
+    Off,
+}
+
+impl From<SyncDirection> for Direction {
+    /// Maps to the direction of an engine bridging X11 to Wayland.
+    fn from(direction: SyncDirection) -> Self {
+        match direction {
+            SyncDirection::Both => Direction::Both,
+            SyncDirection::X11ToWayland => Direction::FirstToSecond,
+            SyncDirection::WaylandToX11 => Direction::SecondToFirst,
+            SyncDirection::Off => Direction::Off,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use clip_bridge::sync::Policy;
+
+    use super::*;
+
+    #[test]
+    fn test_run_arguments_are_parsed() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "clip-bridge",
+            "run",
+            "--selections",
+            "primary",
+            "--direction",
+            "wayland-to-x11",
+            "--display",
+            ":1",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.display.as_deref(), Some(":1"));
+        let Some(Command::Run(args)) = cli.command else {
+            panic!("expected the run command");
+        };
+        let mut config = Config::default();
+        args.apply(&mut config);
+        assert_eq!(config.sync.clipboard, Direction::Off);
+        assert_eq!(config.sync.primary, Direction::SecondToFirst);
+
+        // Settings missing from the command line are left alone
+        let mut config = Config {
+            sync: Policy {
+                clipboard: Direction::FirstToSecond,
+                primary: Direction::Off,
+            },
+            ..Config::default()
+        };
+        let expected = config.sync.clone();
+        RunArgs::default().apply(&mut config);
+        assert_eq!(config.sync, expected);
+
+        // Only --clipboard and --primary turn on a selection the file turns off
+        let cli = Cli::try_parse_from([
+            "clip-bridge",
+            "run",
+            "--selections",
+            "clipboard,primary",
+            "--direction",
+            "wayland-to-x11",
+        ])
+        .unwrap();
+        let Some(Command::Run(args)) = cli.command else {
+            panic!("expected the run command");
+        };
+        args.apply(&mut config);
+        assert_eq!(config.sync.clipboard, Direction::SecondToFirst);
+        assert_eq!(config.sync.primary, Direction::Off);
+        let cli = Cli::try_parse_from(["clip-bridge", "run", "--primary", "both"]).unwrap();
+        let Some(Command::Run(args)) = cli.command else {
+            panic!("expected the run command");
+        };
+        args.apply(&mut config);
+        assert_eq!(config.sync.primary, Direction::Both);
+
+        // Per-selection directions win over the general ones
+        let cli = Cli::try_parse_from([
+            "clip-bridge",
+            "run",
+            "--direction",
+            "x11-to-wayland",
+            "--primary",
+            "off",
+        ])
+        .unwrap();
+        let Some(Command::Run(args)) = cli.command else {
+            panic!("expected the run command");
+        };
+        let mut config = Config::default();
+        args.apply(&mut config);
+        assert_eq!(config.sync.clipboard, Direction::FirstToSecond);
+        assert_eq!(config.sync.primary, Direction::Off);
+    }
+}
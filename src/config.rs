@@ -0,0 +1,372 @@
+//! User configuration, read from a TOML file and reloaded while running.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Deserializer};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::sync::{Mapping, Policy, Way};
+use crate::{ClipboardType, Error, SYNC_MIME_TYPES};
+
+// ============================================================================
+// Config
+// ============================================================================
+
+/// Everything that can be changed without rebuilding, with the compiled-in
+/// behavior as defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Which way each selection is synced.
+    pub sync: Policy,
+    /// Selections also synced into another selection of the other side.
+    pub mappings: Vec<Mapping>,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub mime_types: MimeTypes,
+}
+
+/// How long other clients may take to exchange selection data with us.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Time X11 owners have to answer each conversion request.
+    #[serde(rename = "conversion_reply_ms", deserialize_with = "millis")]
+    pub conversion_reply: Duration,
+    /// Time X11 owners have to send each chunk of an INCR transfer.
+    #[serde(rename = "incr_chunk_ms", deserialize_with = "millis")]
+    pub incr_chunk: Duration,
+    /// Time X11 requestors have to read each chunk we send them.
+    #[serde(rename = "incr_send_ms", deserialize_with = "millis")]
+    pub incr_send: Duration,
+    /// Time Wayland clients have to read or write a selection pipe.
+    #[serde(rename = "pipe_ms", deserialize_with = "millis")]
+    pub pipe: Duration,
+}
+
+/// Bounds on the content being synced.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Content larger than this many bytes, all formats together, isn't synced.
+    pub max_size: Option<usize>,
+}
+
+/// Formats read from selection owners and forwarded to the other side.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MimeTypes {
+    /// Formats synced besides text, in order of preference.
+    pub sync: Vec<String>,
+    /// Formats never synced. Any text alias excludes text.
+    pub exclude: Vec<String>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            conversion_reply: Duration::from_millis(500),
+            incr_chunk: Duration::from_secs(2),
+            incr_send: Duration::from_secs(10),
+            pipe: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        Self {
+            sync: SYNC_MIME_TYPES.iter().map(|m| m.to_string()).collect(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/clip-bridge/config.toml`, with `~/.config` as the
+    /// default configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_dir.join("clip-bridge").join("config.toml"))
+    }
+
+    /// Selections of the other side a `clipboard_type` change going `way` is
+    /// written to.
+    pub fn targets(&self, clipboard_type: &ClipboardType, way: Way) -> Vec<ClipboardType> {
+        let mut targets = Vec::new();
+        if self.sync.allows(clipboard_type, way) {
+            targets.push(clipboard_type.clone());
+        }
+        for mapping in &self.mappings {
+            if mapping.from == *clipboard_type
+                && mapping.direction.allows(way)
+                && !targets.contains(&mapping.to)
+            {
+                targets.push(mapping.to.clone());
+            }
+        }
+        targets
+    }
+
+    /// Reads the configuration file at `path`, which must exist.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(toml) => toml.parse().map_err(|e: Error| e.context(path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::Config(format!("{} does not exist", path.display())))
+            }
+            Err(e) => Err(Error::Io(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Reads the configuration file at `path`, or returns the defaults if
+    /// there is none.
+    pub fn load_or_default(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            debug!("[Config] No configuration at {}", path.display());
+            return Ok(Self::default());
+        }
+        Self::load(path)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(toml: &str) -> Result<Self, Error> {
+        toml::from_str(toml).map_err(|e| Error::Config(e.to_string()))
+    }
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+// ============================================================================
+// Reloading
+// ============================================================================
+
+/// Reloads the configuration at `path` on SIGHUP and whenever the file
+/// changes, passing every valid new version to `on_reload`.
+///
+/// Invalid versions, or the file going away, are logged and otherwise
+/// ignored, so the last valid version stays in effect. Must be called inside
+/// a tokio runtime.
+pub fn watch(
+    path: PathBuf,
+    mut on_reload: impl FnMut(Config) + Send + 'static,
+) -> Result<JoinHandle<()>, Error> {
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| Error::Runtime(format!("Failed to watch SIGHUP: {}", e)))?;
+
+    // Watch the directory, since editors often replace the file rather than
+    // writing to it. Until it exists, watch the closest ancestor that does.
+    let path = std::path::absolute(&path).unwrap_or(path);
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    let target = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && event
+                .paths
+                .iter()
+                .any(|changed| target.starts_with(changed))
+        {
+            let _ = changed_tx.send(event.paths.contains(&target));
+        }
+    })
+    .map_err(|e| Error::Io(format!("Failed to watch configuration: {}", e)))?;
+    let mut watched = None;
+    rewatch(&mut watcher, &path, &mut watched);
+
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = hangup.recv() => info!("[Config] SIGHUP received"),
+                Some(mut file_changed) = changed_rx.recv() => {
+                    // Let the writer finish before reading the file once
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    while let Ok(changed) = changed_rx.try_recv() {
+                        file_changed |= changed;
+                    }
+                    // A directory on the way to the file came or went
+                    rewatch(&mut watcher, &path, &mut watched);
+                    if !file_changed && !path.exists() {
+                        continue;
+                    }
+                    debug!("[Config] {} changed", path.display());
+                }
+                else => break,
+            }
+
+            match Config::load(&path) {
+                Ok(config) => {
+                    info!("[Config] Reloaded {}", path.display());
+                    on_reload(config);
+                }
+                Err(e) => warn!("[Config] Keeping the previous configuration: {}", e),
+            }
+        }
+    }))
+}
+
+/// Moves the watch to the closest existing directory containing `path`, if
+/// that is not the one already `watched`.
+fn rewatch(watcher: &mut impl Watcher, path: &Path, watched: &mut Option<PathBuf>) {
+    let Some(dir) = path.ancestors().skip(1).find(|dir| dir.is_dir()) else {
+        return;
+    };
+    if watched.as_deref() == Some(dir) {
+        return;
+    }
+    if let Some(old) = watched.take() {
+        // Fails if the directory was removed, which ends its watch anyway
+        let _ = watcher.unwatch(&old);
+    }
+    match watcher.watch(dir, RecursiveMode::NonRecursive) {
+        Ok(()) => {
+            debug!("[Config] Watching {}", dir.display());
+            *watched = Some(dir.to_path_buf());
+        }
+        Err(e) => warn!(
+            "[Config] Can't watch {}, reloading on SIGHUP only: {}",
+            dir.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Direction;
+
+    #[test]
+    fn test_empty_file_is_the_default_config() {
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_only_the_default_path_may_be_missing() {
+        let path = Path::new("/nonexistent/clip-bridge/config.toml");
+        assert_eq!(Config::load_or_default(path).unwrap(), Config::default());
+        let err = Config::load(path).unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_config_is_parsed_and_unknown_keys_are_rejected() {
+        let config = r#"
+            [sync]
+            primary = "off"
+            clipboard = "wayland-to-x11"
+
+            [[mappings]]
+            from = "primary"
+            to = "clipboard"
+            direction = "x11-to-wayland"
+
+            [timeouts]
+            pipe_ms = 1500
+
+            [limits]
+            max_size = 1024
+
+            [mime_types]
+            sync = ["image/png"]
+            exclude = ["text/html"]
+        "#
+        .parse::<Config>()
+        .unwrap();
+
+        assert_eq!(config.sync.clipboard, Direction::SecondToFirst);
+        assert_eq!(config.sync.primary, Direction::Off);
+        assert_eq!(
+            config.mappings,
+            [Mapping {
+                from: ClipboardType::Primary,
+                to: ClipboardType::Clipboard,
+                direction: Direction::FirstToSecond,
+            }]
+        );
+        assert_eq!(config.timeouts.pipe, Duration::from_millis(1500));
+        assert_eq!(
+            config.timeouts.conversion_reply,
+            Timeouts::default().conversion_reply
+        );
+        assert_eq!(config.limits.max_size, Some(1024));
+        assert_eq!(config.mime_types.sync, ["image/png"]);
+        assert_eq!(config.mime_types.exclude, ["text/html"]);
+
+        let err = "[timeouts]\npipe = 1500".parse::<Config>().unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_changes_to_the_file_are_reloaded() {
+        let dir = std::env::temp_dir().join(format!("clip-bridge-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[sync]\nprimary = \"off\"").unwrap();
+
+        let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+        let task = watch(path.clone(), move |config| {
+            let _ = reload_tx.send(config);
+        })
+        .unwrap();
+
+        // An invalid version is skipped, the next valid one is picked up
+        std::fs::write(&path, "[sync]\nprimary = \"bogus\"").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        std::fs::write(&path, "[sync]\nclipboard = \"off\"").unwrap();
+
+        let config = tokio::time::timeout(Duration::from_secs(5), reload_rx.recv())
+            .await
+            .expect("configuration was not reloaded")
+            .unwrap();
+        assert_eq!(config.sync.clipboard, Direction::Off);
+        assert_eq!(config.sync.primary, Direction::Both);
+
+        task.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_a_file_in_a_new_directory_is_loaded() {
+        let root = std::env::temp_dir().join(format!("clip-bridge-new-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let dir = root.join("clip-bridge");
+        let path = dir.join("config.toml");
+
+        let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+        let task = watch(path.clone(), move |config| {
+            let _ = reload_tx.send(config);
+        })
+        .unwrap();
+
+        std::fs::create_dir(&dir).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        std::fs::write(&path, "[sync]\nclipboard = \"off\"").unwrap();
+
+        let config = tokio::time::timeout(Duration::from_secs(5), reload_rx.recv())
+            .await
+            .expect("configuration was not loaded")
+            .unwrap();
+        assert_eq!(config.sync.clipboard, Direction::Off);
+
+        task.abort();
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
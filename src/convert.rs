@@ -0,0 +1,72 @@
+// ============================================================================
+// Format Conversion
+// ============================================================================
+
+use crate::ClipboardContent;
+
+/// Adds representations that can be derived from the ones already present.
+///
+/// X11 applications (Wine in particular) often only offer `image/bmp`, while
+/// most Wayland clients only accept `image/png`. With the `image-conversion`
+/// feature enabled, a PNG copy is added whenever only BMP is available.
+#[cfg(feature = "image-conversion")]
+pub fn complete_formats(mut content: ClipboardContent) -> ClipboardContent {
+    use tracing::{debug, warn};
+
+    use crate::{IMAGE_BMP_ATOM, IMAGE_PNG_ATOM};
+
+    if content.get(IMAGE_PNG_ATOM).is_none()
+        && let Some(bmp) = content.get(IMAGE_BMP_ATOM)
+    {
+        match bmp_to_png(bmp) {
+            Ok(png) => {
+                debug!(
+                    "[Convert] Converted {} bytes of BMP to {} bytes of PNG",
+                    bmp.len(),
+                    png.len()
+                );
+                content.insert(IMAGE_PNG_ATOM, png);
+            }
+            Err(e) => warn!("[Convert] Failed to convert BMP to PNG: {}", e),
+        }
+    }
+
+    content
+}
+
+/// Adds representations that can be derived from the ones already present.
+///
+/// Conversion is disabled without the `image-conversion` feature.
+#[cfg(not(feature = "image-conversion"))]
+pub fn complete_formats(content: ClipboardContent) -> ClipboardContent {
+    content
+}
+
+#[cfg(feature = "image-conversion")]
+fn bmp_to_png(bmp: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let image = image::load_from_memory_with_format(bmp, image::ImageFormat::Bmp)?;
+    let mut png = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+#[cfg(all(test, feature = "image-conversion"))]
+mod tests {
+    use super::*;
+    use crate::{IMAGE_BMP_ATOM, IMAGE_PNG_ATOM};
+
+    #[test]
+    fn test_bmp_is_completed_with_png() {
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(2, 2)
+            .write_to(&mut bmp, image::ImageFormat::Bmp)
+            .unwrap();
+        let content =
+            ClipboardContent::from_formats([(IMAGE_BMP_ATOM.to_string(), bmp.into_inner())]);
+
+        let content = complete_formats(content);
+
+        let png = content.get(IMAGE_PNG_ATOM).expect("PNG was not added");
+        assert_eq!(image::guess_format(png).unwrap(), image::ImageFormat::Png);
+    }
+}
@@ -0,0 +1,169 @@
+//! Errors reported by the backends and the sync engine.
+
+use std::fmt;
+
+use wayland_client::backend::WaylandError;
+use wayland_client::{ConnectError as WaylandConnectError, DispatchError};
+use x11rb::errors::{ConnectError as X11ConnectError, ConnectionError, ReplyError, ReplyOrIdError};
+
+// ============================================================================
+// Error
+// ============================================================================
+
+/// What went wrong while talking to a display server or a selection owner.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The display server could not be reached, or the connection broke.
+    #[error("connection lost: {0}")]
+    ConnectionLost(String),
+    /// The display server or another client sent something unexpected.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// Selection data could not be decoded.
+    #[error("encoding error: {0}")]
+    Encoding(String),
+    /// The display server lacks an extension or protocol the bridge needs.
+    #[error("missing protocol: {0}")]
+    MissingProtocol(String),
+    /// An event loop was started outside a tokio runtime.
+    #[error("runtime error: {0}")]
+    Runtime(String),
+    /// Reading or writing a local file or stream failed.
+    #[error("I/O error: {0}")]
+    Io(String),
+    /// The configuration file is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+}
+
+impl Error {
+    /// Returns `true` if the connection must be re-established to recover.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::ConnectionLost(_))
+    }
+
+    /// Prefixes the message with `context`, keeping the kind of error.
+    pub(crate) fn context(self, context: impl fmt::Display) -> Self {
+        match self {
+            Self::ConnectionLost(e) => Self::ConnectionLost(format!("{}: {}", context, e)),
+            Self::Protocol(e) => Self::Protocol(format!("{}: {}", context, e)),
+            Self::Encoding(e) => Self::Encoding(format!("{}: {}", context, e)),
+            Self::MissingProtocol(e) => Self::MissingProtocol(format!("{}: {}", context, e)),
+            Self::Runtime(e) => Self::Runtime(format!("{}: {}", context, e)),
+            Self::Io(e) => Self::Io(format!("{}: {}", context, e)),
+            Self::Config(e) => Self::Config(format!("{}: {}", context, e)),
+        }
+    }
+}
+
+/// Converts errors of the display server libraries, describing what failed.
+pub(crate) trait ResultExt<T> {
+    fn context(self, context: impl fmt::Display) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: impl fmt::Display) -> Result<T, Error> {
+        self.map_err(|e| e.into().context(context))
+    }
+}
+
+// ============================================================================
+// X11 Errors
+// ============================================================================
+
+impl From<X11ConnectError> for Error {
+    fn from(e: X11ConnectError) -> Self {
+        Self::ConnectionLost(e.to_string())
+    }
+}
+
+impl From<ConnectionError> for Error {
+    fn from(e: ConnectionError) -> Self {
+        match e {
+            ConnectionError::UnsupportedExtension => Self::MissingProtocol(e.to_string()),
+            ConnectionError::IoError(_) | ConnectionError::UnknownError => {
+                Self::ConnectionLost(e.to_string())
+            }
+            _ => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<ReplyError> for Error {
+    fn from(e: ReplyError) -> Self {
+        match e {
+            ReplyError::ConnectionError(e) => e.into(),
+            ReplyError::X11Error(e) => Self::Protocol(format!("{:?}", e.error_kind)),
+        }
+    }
+}
+
+impl From<ReplyOrIdError> for Error {
+    fn from(e: ReplyOrIdError) -> Self {
+        match e {
+            ReplyOrIdError::ConnectionError(e) => e.into(),
+            ReplyOrIdError::X11Error(e) => Self::Protocol(format!("{:?}", e.error_kind)),
+            ReplyOrIdError::IdsExhausted => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+// ============================================================================
+// Wayland Errors
+// ============================================================================
+
+impl From<WaylandConnectError> for Error {
+    fn from(e: WaylandConnectError) -> Self {
+        Self::ConnectionLost(e.to_string())
+    }
+}
+
+impl From<WaylandError> for Error {
+    fn from(e: WaylandError) -> Self {
+        match e {
+            WaylandError::Io(_) => Self::ConnectionLost(e.to_string()),
+            WaylandError::Protocol(_) => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl From<DispatchError> for Error {
+    fn from(e: DispatchError) -> Self {
+        match e {
+            DispatchError::Backend(e) => e.into(),
+            DispatchError::BadMessage { .. } => Self::Protocol(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_keeps_kind() {
+        let io = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        let result: Result<(), _> = Err(ConnectionError::IoError(io));
+
+        let error = result.context("Failed to flush connection").unwrap_err();
+        assert!(error.is_connection_lost());
+        assert!(
+            error
+                .to_string()
+                .starts_with("connection lost: Failed to flush connection: ")
+        );
+    }
+
+    #[test]
+    fn test_x11_errors_are_classified() {
+        assert!(matches!(
+            Error::from(ConnectionError::UnsupportedExtension),
+            Error::MissingProtocol(_)
+        ));
+        assert!(matches!(
+            Error::from(ReplyOrIdError::IdsExhausted),
+            Error::Protocol(_)
+        ));
+    }
+}
@@ -1,34 +1,158 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+pub mod backend;
+pub mod config;
+pub mod convert;
+pub mod error;
+pub mod reconnect;
+pub mod sync;
 pub mod wayland;
 pub mod x11;
 
+pub use error::Error;
+
 // ============================================================================
 // Shared State
 // ============================================================================
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum ClipboardContent {
-    Text(String),
+    /// Every representation offered for a selection, keyed by MIME type.
+    ///
+    /// Text is always stored under [`TEXT_PLAIN_UTF8_ATOM`], regardless of the
+    /// target it was read from.
+    Mime(BTreeMap<String, Vec<u8>>),
     Empty,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl ClipboardContent {
+    /// Creates content holding a single UTF-8 text representation.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::from_formats([(TEXT_PLAIN_UTF8_ATOM.to_string(), text.into().into_bytes())])
+    }
+
+    /// Creates content from `(mime_type, bytes)` pairs, dropping empty
+    /// representations and storing text aliases as one format.
+    pub fn from_formats(formats: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        let formats = formats
+            .into_iter()
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(mime_type, data)| (format_key(&mime_type).to_string(), data))
+            .collect::<BTreeMap<_, _>>();
+        if formats.is_empty() {
+            Self::Empty
+        } else {
+            Self::Mime(formats)
+        }
+    }
+
+    /// Returns the bytes stored for `mime_type`, treating all text aliases as the same format.
+    pub fn get(&self, mime_type: &str) -> Option<&[u8]> {
+        let Self::Mime(formats) = self else {
+            return None;
+        };
+        formats.get(format_key(mime_type)).map(Vec::as_slice)
+    }
+
+    /// Adds or replaces the representation stored for `mime_type`, treating
+    /// all text aliases as the same format.
+    pub fn insert(&mut self, mime_type: impl Into<String>, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        match self {
+            Self::Mime(formats) => {
+                formats.insert(format_key(&mime_type.into()).to_string(), data);
+            }
+            Self::Empty => *self = Self::from_formats([(mime_type.into(), data)]),
+        }
+    }
+
+    /// Drops the representation stored for `mime_type`, treating all text
+    /// aliases as the same format.
+    pub fn remove(&mut self, mime_type: &str) {
+        let Self::Mime(formats) = self else {
+            return;
+        };
+        formats.remove(format_key(mime_type));
+        if formats.is_empty() {
+            *self = Self::Empty;
+        }
+    }
+
+    /// Returns the text representation, if any.
+    pub fn text_content(&self) -> Option<&str> {
+        self.get(TEXT_PLAIN_UTF8_ATOM)
+            .and_then(|data| std::str::from_utf8(data).ok())
+    }
+
+    pub fn has_text(&self) -> bool {
+        self.get(TEXT_PLAIN_UTF8_ATOM).is_some()
+    }
+
+    /// Returns the MIME types held by this content.
+    pub fn mime_types(&self) -> impl Iterator<Item = &str> {
+        let formats = match self {
+            Self::Mime(formats) => Some(formats),
+            Self::Empty => None,
+        };
+        formats
+            .into_iter()
+            .flat_map(|f| f.keys().map(String::as_str))
+    }
+
+    /// Total size of all representations in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Mime(formats) => formats.values().map(Vec::len).sum(),
+            Self::Empty => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for ClipboardContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mime(formats) => f
+                .debug_map()
+                .entries(
+                    formats
+                        .iter()
+                        .map(|(mime, data)| (mime, format!("{} bytes", data.len()))),
+                )
+                .finish(),
+            Self::Empty => write!(f, "Empty"),
+        }
+    }
+}
+
+/// Returns the key `mime_type` is stored under, the same for all text aliases.
+fn format_key(mime_type: &str) -> &str {
+    if is_text_mime(mime_type) {
+        TEXT_PLAIN_UTF8_ATOM
+    } else {
+        mime_type
+    }
+}
+
+/// Returns `true` if `mime_type` is one of the names text is exchanged under.
+pub fn is_text_mime(mime_type: &str) -> bool {
+    TEXT_MIME_TYPES.contains(&mime_type)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ClipboardType {
     Clipboard,
     Primary,
 }
 
-#[derive(Debug)]
-pub enum SyncEvent {
-    X11ToWayland {
-        content: ClipboardContent,
-        clipboard_type: ClipboardType,
-    },
-    WaylandToX11 {
-        content: ClipboardContent,
-        clipboard_type: ClipboardType,
-    },
-}
-
 // ============================================================================
 // Configuration
 // ============================================================================
@@ -38,8 +162,95 @@ pub const PRIMARY_ATOM: &str = "PRIMARY";
 pub const TARGETS_ATOM: &str = "TARGETS";
 pub const MULTIPLE_ATOM: &str = "MULTIPLE";
 pub const INCR_ATOM: &str = "INCR";
+pub const TIMESTAMP_ATOM: &str = "TIMESTAMP";
 pub const UTF8_STRING_ATOM: &str = "UTF8_STRING";
 pub const TEXT_ATOM: &str = "TEXT";
 pub const STRING_ATOM: &str = "STRING";
 pub const TEXT_PLAIN_UTF8_ATOM: &str = "text/plain;charset=utf-8";
 pub const TEXT_PLAIN_ATOM: &str = "text/plain";
+pub const TEXT_HTML_ATOM: &str = "text/html";
+pub const TEXT_URI_LIST_ATOM: &str = "text/uri-list";
+pub const IMAGE_PNG_ATOM: &str = "image/png";
+pub const IMAGE_JPEG_ATOM: &str = "image/jpeg";
+pub const IMAGE_BMP_ATOM: &str = "image/bmp";
+pub const CLIP_BRIDGE_PROPERTY_ATOM: &str = "CLIP_BRIDGE_SELECTION";
+pub const CLIP_BRIDGE_PRIMARY_PROPERTY_ATOM: &str = "CLIP_BRIDGE_PRIMARY";
+pub const CLIP_BRIDGE_TIMESTAMP_ATOM: &str = "CLIP_BRIDGE_TIMESTAMP";
+
+/// Names text may be requested or offered under, in order of preference.
+pub const TEXT_MIME_TYPES: &[&str] = &[
+    TEXT_PLAIN_UTF8_ATOM,
+    UTF8_STRING_ATOM,
+    TEXT_PLAIN_ATOM,
+    STRING_ATOM,
+    TEXT_ATOM,
+];
+
+/// Non-text formats synced between X11 and Wayland.
+pub const SYNC_MIME_TYPES: &[&str] = &[
+    TEXT_HTML_ATOM,
+    TEXT_URI_LIST_ATOM,
+    IMAGE_PNG_ATOM,
+    IMAGE_JPEG_ATOM,
+    IMAGE_BMP_ATOM,
+];
+
+/// Format the selections we set are also offered as, naming our process.
+///
+/// Selections offering it were set by us, even when they come back through
+/// another client such as XWayland, and are never synced again.
+pub fn origin_mime_type() -> &'static str {
+    static ORIGIN: OnceLock<String> = OnceLock::new();
+    ORIGIN.get_or_init(|| {
+        format!(
+            "application/x-clip-bridge-origin;pid={}",
+            std::process::id()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_aliases_resolve_to_same_data() {
+        let content = ClipboardContent::text("hello");
+
+        for mime_type in TEXT_MIME_TYPES {
+            assert_eq!(content.get(mime_type), Some("hello".as_bytes()));
+        }
+        assert_eq!(content.text_content(), Some("hello"));
+        assert_eq!(content.get(TEXT_HTML_ATOM), None);
+    }
+
+    #[test]
+    fn test_text_aliases_are_stored_as_one_format() {
+        let mut content =
+            ClipboardContent::from_formats([(UTF8_STRING_ATOM.to_string(), b"hello".to_vec())]);
+        assert!(content.has_text());
+        assert_eq!(content.get(UTF8_STRING_ATOM), Some("hello".as_bytes()));
+
+        content.insert(TEXT_PLAIN_ATOM, b"world".to_vec());
+        assert_eq!(
+            content.mime_types().collect::<Vec<_>>(),
+            [TEXT_PLAIN_UTF8_ATOM]
+        );
+        assert_eq!(content.text_content(), Some("world"));
+    }
+
+    #[test]
+    fn test_from_formats_drops_empty_representations() {
+        let content = ClipboardContent::from_formats([
+            (TEXT_HTML_ATOM.to_string(), b"<b>hi</b>".to_vec()),
+            (TEXT_URI_LIST_ATOM.to_string(), Vec::new()),
+        ]);
+
+        assert_eq!(content.mime_types().collect::<Vec<_>>(), [TEXT_HTML_ATOM]);
+        assert!(!content.has_text());
+        assert_eq!(
+            ClipboardContent::from_formats([(TEXT_HTML_ATOM.to_string(), Vec::new())]),
+            ClipboardContent::Empty
+        );
+    }
+}
@@ -2,237 +2,349 @@
 //!
 //! This program synchronizes clipboard content between X11 and Wayland compositors.
 
+mod cli;
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::Parser;
 use clip_bridge::{
-    ClipboardContent, ClipboardType, SyncEvent,
-    wayland::{GlobalData, WaylandState},
+    ClipboardContent, ClipboardType, Error,
+    backend::{ChannelBackend, ClipboardBackend, SyncEndpoint},
+    config::{self, Config},
+    convert, is_text_mime,
+    sync::SyncEngine,
+    wayland::{self, GlobalData, WaylandState},
     x11::X11State,
 };
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info};
+
+use crate::cli::{Cli, Command, CopyArgs, PasteArgs, RunArgs, Side};
+
+/// How long `paste` waits for the selection to be read.
+const PASTE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Display servers to connect to, `None` meaning the one of the environment.
+#[derive(Debug, Clone, Default)]
+struct Displays {
+    x11: Option<String>,
+    wayland: Option<String>,
+}
+
 // ============================================================================
 // Main Application
 // ============================================================================
-//
-use tracing::{debug, error, info};
-use wayland_client::{Connection, DispatchError};
-
-use tokio::{sync::mpsc, task::JoinHandle};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt().init();
+async fn main() -> Result<ExitCode, Error> {
+    let cli = Cli::parse();
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Run(RunArgs::default()));
+
+    // Initialize logging, keeping stdout for pasted content
+    let default_level = match command {
+        Command::Run(_) => LevelFilter::INFO,
+        Command::Copy(_) | Command::Paste(_) => LevelFilter::WARN,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level.unwrap_or(default_level))
+        .with_writer(std::io::stderr)
+        .init();
+
+    let displays = Displays {
+        x11: cli.display,
+        wayland: cli.wayland_display,
+    };
+    // Only the default configuration file is optional
+    let config_path = cli.config.clone().or_else(Config::default_path);
+    let config = match (&cli.config, &config_path) {
+        (Some(path), _) => Config::load(path)?,
+        (None, Some(path)) => Config::load_or_default(path)?,
+        (None, None) => Config::default(),
+    };
+    match command {
+        Command::Run(args) => run(displays, config_path, config, args)
+            .await
+            .map(|()| ExitCode::SUCCESS),
+        Command::Copy(args) => copy(displays, config, args).await,
+        Command::Paste(args) => paste(displays, config, args).await,
+    }
+}
 
+/// Syncs selections between X11 and Wayland until both sides stop,
+/// reloading the configuration at `config_path` when it changes.
+async fn run(
+    displays: Displays,
+    config_path: Option<PathBuf>,
+    mut config: Config,
+    args: RunArgs,
+) -> Result<(), Error> {
     info!("Starting X11 <-> Wayland Clipboard Bridge");
 
-    // Create channels for sync events
-    let (x11_to_wayland_tx, mut x11_to_wayland_rx) = mpsc::unbounded_channel::<SyncEvent>();
-    let (wayland_to_x11_tx, mut wayland_to_x11_rx) = mpsc::unbounded_channel::<SyncEvent>();
+    // Command line options take precedence over every version of the file
+    args.apply(&mut config);
+    let (config_tx, config_rx) = watch::channel(config);
+    match config_path {
+        Some(path) => {
+            config::watch(path, move |mut config| {
+                args.apply(&mut config);
+                config_tx.send_if_modified(|current| {
+                    let modified = *current != config;
+                    *current = config;
+                    modified
+                });
+            })?;
+        }
+        None => debug!("[Config] No configuration directory, not watching for changes"),
+    }
+
+    // Create both sides of the bridge and the sync engine connecting them
+    let (x11_backend, x11_endpoint) = ChannelBackend::new("X11", X11State::capabilities());
+    let (wayland_backend, wayland_endpoint) =
+        ChannelBackend::new("Wayland", WaylandState::capabilities());
+    let mut sync_engine = SyncEngine::new(x11_backend, wayland_backend).config(config_rx.clone());
+
+    let x11_handle = spawn_x11(displays.x11, x11_endpoint, config_rx.clone(), None);
+    let wayland_handle = spawn_wayland(displays.wayland, wayland_endpoint, config_rx)?;
+
+    let mut sides = JoinSet::new();
+    sides.spawn(async move { ("X11", join(x11_handle).await) });
+    sides.spawn(async move { ("Wayland", join(wayland_handle).await) });
+
+    // Forward clipboard changes between both sides
+    sync_engine.start();
+
+    // Run until interrupted, or until either side stops for good
+    let mut terminate = signal(SignalKind::terminate())
+        .map_err(|e| Error::Runtime(format!("Failed to watch SIGTERM: {}", e)))?;
+    let result = tokio::select! {
+        Some(Ok((side, result))) = sides.join_next() => side_stopped(side, result),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminate.recv() => Ok(()),
+    };
+
+    // Stopping the engine closes the command channels, which ends the event
+    // loops still running
+    info!("Clipboard bridge shutting down");
+    sync_engine.stop().await;
+    while let Some(Ok((side, side_result))) = sides.join_next().await {
+        if let Err(e) = side_result {
+            error!("[{}] Event loop error: {}", side, e);
+        }
+    }
+    result
+}
+
+/// Turns `side` stopping on its own into the error ending the bridge.
+fn side_stopped(side: &str, result: Result<(), Error>) -> Result<(), Error> {
+    let e = result
+        .err()
+        .unwrap_or_else(|| Error::Runtime(format!("{} event loop stopped", side)));
+    error!("[{}] {}, stopping the bridge", side, e);
+    Err(e)
+}
+
+// ============================================================================
+// One-off Commands
+// ============================================================================
+
+/// Sets a selection to standard input and serves it until interrupted.
+async fn copy(displays: Displays, config: Config, args: CopyArgs) -> Result<ExitCode, Error> {
+    let mut data = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut data)
+        .map_err(|e| Error::Io(format!("Failed to read standard input: {}", e)))?;
+    let content = if is_text_mime(&args.mime_type) {
+        let text = String::from_utf8(data)
+            .map_err(|e| Error::Encoding(format!("Standard input is not UTF-8: {}", e)))?;
+        ClipboardContent::text(text)
+    } else {
+        convert::complete_formats(ClipboardContent::from_formats([(args.mime_type, data)]))
+    };
+    if content.is_empty() {
+        eprintln!("clip-bridge: nothing to copy");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let (backend, handle) = start_backend(args.to, &displays, config, None)?;
+    backend.write(content, args.selection.into())?;
 
-    // Create channels for setting clipboard
-    let (set_x11_clipboard_tx, set_x11_clipboard_rx) =
-        mpsc::unbounded_channel::<(String, ClipboardType)>();
-    let (set_wayland_clipboard_tx, set_wayland_clipboard_rx) =
-        mpsc::unbounded_channel::<(String, ClipboardType)>();
+    let mut terminate = signal(SignalKind::terminate())
+        .map_err(|e| Error::Runtime(format!("Failed to watch SIGTERM: {}", e)))?;
+    tokio::select! {
+        result = join(handle) => result?,
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Clone for X11 thread
-    let x11_sync_tx = x11_to_wayland_tx.clone();
-    let wayland_sync_tx = wayland_to_x11_tx.clone();
+/// Writes the content of a selection, or the MIME types it was read as, to
+/// standard output.
+async fn paste(displays: Displays, config: Config, args: PasteArgs) -> Result<ExitCode, Error> {
+    let clipboard_type = ClipboardType::from(args.selection);
+    let (mut backend, handle) =
+        start_backend(args.from, &displays, config, Some(clipboard_type.clone()))?;
+    let mut changes = backend.changes().expect("change stream of a new backend");
 
-    // Spawn X11 thread
-    let x11_handle = tokio::task::spawn_blocking(move || {
+    let received = async {
+        while let Some((content, changed)) = changes.recv().await {
+            if changed == clipboard_type {
+                return Some(content);
+            }
+        }
+        None
+    };
+    let content = match tokio::time::timeout(PASTE_TIMEOUT, received).await {
+        Ok(Some(content)) => Some(content),
+        // The change stream closes when the event loop stops, report why
+        Ok(None) => {
+            join(handle).await?;
+            None
+        }
+        Err(_) => None,
+    };
+
+    let Some(content) = content else {
+        eprintln!("clip-bridge: {:?} has no content", clipboard_type);
+        return Ok(ExitCode::FAILURE);
+    };
+    let output = if args.list_types {
+        content
+            .mime_types()
+            .flat_map(|mime_type| [mime_type.as_bytes(), b"\n"])
+            .flatten()
+            .copied()
+            .collect()
+    } else if let Some(data) = content.get(&args.mime_type) {
+        data.to_vec()
+    } else {
+        eprintln!(
+            "clip-bridge: {:?} is not available as {}",
+            clipboard_type, args.mime_type
+        );
+        return Ok(ExitCode::FAILURE);
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&output)
+        .and_then(|()| stdout.flush())
+        .map_err(|e| Error::Io(format!("Failed to write standard output: {}", e)))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// ============================================================================
+// Backends
+// ============================================================================
+
+/// Starts the backend of `side` alone, along with its event loop.
+///
+/// `fetch` is a selection to read right away rather than once it changes.
+fn start_backend(
+    side: Side,
+    displays: &Displays,
+    config: Config,
+    fetch: Option<ClipboardType>,
+) -> Result<(ChannelBackend, JoinHandle<Result<(), Error>>), Error> {
+    // One-off commands don't live long enough for reloading to matter
+    let config = watch::channel(config).1;
+    match side {
+        Side::X11 => {
+            let (backend, endpoint) = ChannelBackend::new("X11", X11State::capabilities());
+            let handle = spawn_x11(displays.x11.clone(), endpoint, config, fetch);
+            Ok((backend, handle))
+        }
+        Side::Wayland => {
+            // The compositor announces the current selections on its own
+            let (backend, endpoint) = ChannelBackend::new("Wayland", WaylandState::capabilities());
+            let handle = spawn_wayland(displays.wayland.clone(), endpoint, config)?;
+            Ok((backend, handle))
+        }
+    }
+}
+
+/// Waits for an event loop, reporting a panic as an error.
+async fn join(handle: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+    handle
+        .await
+        .map_err(|e| Error::Runtime(format!("Event loop task failed: {}", e)))?
+}
+
+/// Runs the X11 side on a blocking thread.
+fn spawn_x11(
+    display: Option<String>,
+    endpoint: SyncEndpoint,
+    config: watch::Receiver<Config>,
+    fetch: Option<ClipboardType>,
+) -> JoinHandle<Result<(), Error>> {
+    tokio::task::spawn_blocking(move || {
         info!("[X11] Initializing X11 connection");
 
-        let (conn, screen_num) =
-            x11rb::connect(None).map_err(|e| format!("Failed to connect to X11: {}", e))?;
-        let mut x11_state = X11State::new(conn, screen_num, x11_sync_tx, set_x11_clipboard_rx)
-            .map_err(|e| format!("Failed to create X11 state: {}", e))?;
+        let (conn, screen_num) = x11rb::connect(display.as_deref())?;
+        let mut x11_state = X11State::new(
+            conn,
+            screen_num,
+            endpoint.sync_tx,
+            endpoint.set_clipboard_rx,
+        )?;
+
+        x11_state.set_config(config)?;
 
         info!("[X11] Connection established, window: {}", x11_state.window);
 
-        // Run X11 event loop
-        // Note: We don't request clipboard content here on startup.
-        // Instead, we wait for XFixes selection events which indicate
-        // when another application owns the selection. This avoids the
-        // race condition where we request content before any app has set it.
-        if let Err(e) = x11_state.run_event_loop() {
-            error!("[X11] Event loop error: {}", e);
+        // Note: We don't request clipboard content here on startup unless
+        // asked to. Instead, we wait for XFixes selection events which
+        // indicate when another application owns the selection. This avoids
+        // the race condition where we request content before any app has set it.
+        if let Some(clipboard_type) = fetch {
+            x11_state.request_clipboard_content(clipboard_type, x11rb::CURRENT_TIME)?;
         }
 
-        Ok::<(), String>(())
-    });
+        // Run X11 event loop, reconnecting if the X server restarts
+        x11_state.run_with_reconnect(|| Ok(x11rb::connect(display.as_deref())?))
+    })
+}
 
-    // Initialize Wayland
+/// Connects to the compositor, then runs the Wayland side on a blocking thread.
+fn spawn_wayland(
+    display: Option<String>,
+    endpoint: SyncEndpoint,
+    config: watch::Receiver<Config>,
+) -> Result<JoinHandle<Result<(), Error>>, Error> {
     info!("[Wayland] Initializing Wayland connection");
 
-    let wayland_conn = Connection::connect_to_env()?;
-    let display = wayland_conn.display();
+    let wayland_conn = wayland::connect(display.as_deref())?;
     let mut event_queue = wayland_conn.new_event_queue();
     let qh = event_queue.handle();
 
-    let mut wayland_state = WaylandState::new(
-        qh.clone(),
-        wayland_sync_tx,
-        set_wayland_clipboard_tx.clone(),
-    );
+    let mut wayland_state = WaylandState::new(qh.clone(), endpoint.sync_tx);
+    wayland_state.set_config(config);
 
     // Get registry
-    display.get_registry(&qh, GlobalData);
+    wayland_conn.display().get_registry(&qh, GlobalData);
 
     // Roundtrip to initialize globals
-    event_queue.roundtrip(&mut wayland_state)?;
+    event_queue
+        .roundtrip(&mut wayland_state)
+        .map_err(Error::from)?;
 
     info!("[Wayland] Connection established");
 
-    // Main sync loop
-    let wayland_handle: JoinHandle<Result<(), DispatchError>> =
-        tokio::task::spawn_blocking(move || {
-            let mut set_wayland_clipboard_rx = set_wayland_clipboard_rx;
-            loop {
-                if let Ok((content, clipboard_type)) = set_wayland_clipboard_rx.try_recv() {
-                    wayland_state.set_clipboard_content(content, clipboard_type);
-                }
-
-                event_queue.roundtrip(&mut wayland_state)?;
-
-                if let Err(e) = event_queue.dispatch_pending(&mut wayland_state) {
-                    error!("[Wayland] Dispatch error: {}", e);
-                }
-            }
-        });
-
-    // Handle sync events in main task
-    tokio::spawn(async move {
-        let mut x11_content: Option<String> = None;
-        let mut primary_content: Option<String> = None;
-
-        info!("[Sync] Starting sync loop");
-
-        loop {
-            tokio::select! {
-                Some(event) = x11_to_wayland_rx.recv() => {
-                    debug!("[Sync] Received event from X11: {:?}", event);
-                    match event {
-                        SyncEvent::X11ToWayland { content, clipboard_type } => {
-                            debug!("[Sync] Matching content: {:?}", content);
-                            match content {
-                                ClipboardContent::Text(text) => {
-                                    debug!("[Sync] X11 text content: {:?}", text);
-                                    debug!("[Sync] Current x11_content: {:?}", x11_content);
-                                    match clipboard_type {
-                                        ClipboardType::Clipboard => {
-                                            if x11_content.as_ref() != Some(&text) {
-                                                info!("[Sync] X11 -> Wayland clipboard: {} chars", text.len());
-                                                x11_content = Some(text.clone());
-                                                debug!("[Sync] Sending to Wayland clipboard channel");
-                                                match set_wayland_clipboard_tx.send((text, ClipboardType::Clipboard)) {
-                                                    Ok(_) => debug!("[Sync] Sent to Wayland clipboard channel successfully"),
-                                                    Err(e) => error!("[Sync] Failed to send to Wayland clipboard channel: {}", e),
-                                                }
-                                            } else {
-                                                debug!("[Sync] X11 clipboard content unchanged, skipping");
-                                            }
-                                        }
-                                        ClipboardType::Primary => {
-                                            if primary_content.as_ref() != Some(&text) {
-                                                info!("[Sync] X11 -> Wayland primary: {} chars", text.len());
-                                                primary_content = Some(text.clone());
-                                                debug!("[Sync] Sending to Wayland primary channel");
-                                                match set_wayland_clipboard_tx.send((text, ClipboardType::Primary)) {
-                                                    Ok(_) => debug!("[Sync] Sent to Wayland primary channel successfully"),
-                                                    Err(e) => error!("[Sync] Failed to send to Wayland primary channel: {}", e),
-                                                }
-                                            } else {
-                                                debug!("[Sync] X11 primary content unchanged, skipping");
-                                            }
-                                        }
-                                    }
-                                }
-                                ClipboardContent::Empty => {
-                                    debug!("[Sync] X11 empty content");
-                                    match clipboard_type {
-                                        ClipboardType::Clipboard => {
-                                            x11_content = None;
-                                        }
-                                        ClipboardType::Primary => {
-                                            primary_content = None;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            debug!("[Sync] Unhandled event from X11: {:?}", event);
-                        }
-                    }
-                }
-                Some(event) = wayland_to_x11_rx.recv() => {
-                    debug!("[Sync] Received event from Wayland: {:?}", event);
-                    match event {
-                        SyncEvent::WaylandToX11 { content, clipboard_type } => {
-                            debug!("[Sync] Matching Wayland content: {:?}", content);
-                            match content {
-                                ClipboardContent::Text(text) => {
-                                    debug!("[Sync] Wayland text content: {:?}", text);
-                                    match clipboard_type {
-                                        ClipboardType::Clipboard => {
-                                            if x11_content.as_ref() != Some(&text) {
-                                                info!("[Sync] Wayland -> X11 clipboard: {} chars", text.len());
-                                                x11_content = Some(text.clone());
-                                                debug!("[Sync] Sending to X11 clipboard channel");
-                                                match set_x11_clipboard_tx.send((text, ClipboardType::Clipboard)) {
-                                                    Ok(_) => debug!("[Sync] Sent to X11 clipboard channel successfully"),
-                                                    Err(e) => error!("[Sync] Failed to send to X11 clipboard channel: {}", e),
-                                                }
-                                            } else {
-                                                debug!("[Sync] Wayland clipboard content unchanged, skipping");
-                                            }
-                                        }
-                                        ClipboardType::Primary => {
-                                            if primary_content.as_ref() != Some(&text) {
-                                                info!("[Sync] Wayland -> X11 primary: {} chars", text.len());
-                                                primary_content = Some(text.clone());
-                                                debug!("[Sync] Sending to X11 primary channel");
-                                                match set_x11_clipboard_tx.send((text, ClipboardType::Primary)) {
-                                                    Ok(_) => debug!("[Sync] Sent to X11 primary channel successfully"),
-                                                    Err(e) => error!("[Sync] Failed to send to X11 primary channel: {}", e),
-                                                }
-                                            } else {
-                                                debug!("[Sync] Wayland primary content unchanged, skipping");
-                                            }
-                                        }
-                                    }
-                                }
-                                ClipboardContent::Empty => {
-                                    debug!("[Sync] Wayland empty content");
-                                    match clipboard_type {
-                                        ClipboardType::Clipboard => {
-                                            x11_content = None;
-                                        }
-                                        ClipboardType::Primary => {
-                                            primary_content = None;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        _ => {
-                            debug!("[Sync] Unhandled event from Wayland: {:?}", event);
-                        }
-                    }
-                }
-            }
-        }
-    });
-
-    // Wait for tasks
-    let (x11_result, wayland_result) = tokio::join!(x11_handle, wayland_handle);
-
-    if let Err(e) = x11_result {
-        error!("X11 task error: {:?}", e);
-    }
-    if let Err(e) = wayland_result {
-        error!("Wayland task error: {:?}", e);
-    }
-
-    info!("Clipboard bridge shutting down");
-    Ok(())
+    // Run Wayland event loop, reconnecting if the compositor restarts
+    Ok(tokio::task::spawn_blocking(move || {
+        wayland_state.run_with_reconnect(
+            wayland_conn,
+            event_queue,
+            endpoint.set_clipboard_rx,
+            || wayland::connect(display.as_deref()),
+        )
+    }))
 }
@@ -0,0 +1,601 @@
+//! Routing of clipboard content between two backends.
+
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+use crate::backend::ClipboardBackend;
+use crate::config::Config;
+use crate::{ClipboardContent, ClipboardType};
+
+// ============================================================================
+// Sync Engine
+// ============================================================================
+
+/// Writes a change of `source` to `target`, the `way` it goes, unless
+/// `config` rules it out.
+fn forward(
+    source: &dyn ClipboardBackend,
+    target: &dyn ClipboardBackend,
+    way: Way,
+    config: &Config,
+    mut content: ClipboardContent,
+    clipboard_type: ClipboardType,
+) {
+    debug!(
+        "[Sync] Received {:?} change from {}: {:?}",
+        clipboard_type,
+        source.name(),
+        content
+    );
+
+    let targets: Vec<ClipboardType> = config
+        .targets(&clipboard_type, way)
+        .into_iter()
+        .filter(|target_type| {
+            let supported = target.capabilities().supports_selection(target_type);
+            if !supported {
+                debug!(
+                    "[Sync] {} has no {:?} selection, skipping",
+                    target.name(),
+                    target_type
+                );
+            }
+            supported
+        })
+        .collect();
+    if targets.is_empty() {
+        debug!(
+            "[Sync] {:?} is not synced {:?}, skipping",
+            clipboard_type, way
+        );
+        return;
+    }
+    for mime_type in &config.mime_types.exclude {
+        content.remove(mime_type);
+    }
+    let unsupported = content
+        .mime_types()
+        .filter(|mime_type| !target.capabilities().supports_mime_type(mime_type))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    for mime_type in unsupported {
+        debug!(
+            "[Sync] {} can't take {}, dropping it",
+            target.name(),
+            mime_type
+        );
+        content.remove(&mime_type);
+    }
+    if let Some(max_size) = config.limits.max_size
+        && content.len() > max_size
+    {
+        info!(
+            "[Sync] {:?} content from {} exceeds {} bytes, skipping",
+            clipboard_type,
+            source.name(),
+            max_size
+        );
+        return;
+    }
+
+    if content.is_empty() {
+        debug!("[Sync] {:?} content empty, skipping", clipboard_type);
+        return;
+    }
+
+    for target_type in targets {
+        info!(
+            "[Sync] {} {:?} -> {} {:?}: {} bytes",
+            source.name(),
+            clipboard_type,
+            target.name(),
+            target_type,
+            content.len()
+        );
+        match target.write(content.clone(), target_type) {
+            Ok(()) => debug!("[Sync] Sent to {} successfully", target.name()),
+            Err(e) => error!("[Sync] Failed to send to {}: {}", target.name(), e),
+        }
+    }
+}
+
+/// Which way changes are forwarded between the two backends of a [`SyncEngine`].
+///
+/// Configuration files name the directions after X11 and Wayland, the first
+/// and second backends of the bridge.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    #[default]
+    Both,
+    /// Only from the first backend to the second.
+    #[serde(rename = "x11-to-wayland")]
+    FirstToSecond,
+    /// Only from the second backend to the first.
+    #[serde(rename = "wayland-to-x11")]
+    SecondToFirst,
+    /// Not at all.
+    Off,
+}
+
+impl Direction {
+    /// Whether changes going `way` are forwarded.
+    pub fn allows(self, way: Way) -> bool {
+        match self {
+            Direction::Both => true,
+            Direction::FirstToSecond => way == Way::FirstToSecond,
+            Direction::SecondToFirst => way == Way::SecondToFirst,
+            Direction::Off => false,
+        }
+    }
+}
+
+/// The way a single change goes between the two backends of a [`SyncEngine`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Way {
+    FirstToSecond,
+    SecondToFirst,
+}
+
+/// Which way each selection is synced.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub clipboard: Direction,
+    pub primary: Direction,
+}
+
+impl Policy {
+    /// The same `direction` for every selection.
+    pub fn uniform(direction: Direction) -> Self {
+        Self {
+            clipboard: direction,
+            primary: direction,
+        }
+    }
+
+    pub fn direction(&self, clipboard_type: &ClipboardType) -> Direction {
+        match clipboard_type {
+            ClipboardType::Clipboard => self.clipboard,
+            ClipboardType::Primary => self.primary,
+        }
+    }
+
+    pub fn direction_mut(&mut self, clipboard_type: &ClipboardType) -> &mut Direction {
+        match clipboard_type {
+            ClipboardType::Clipboard => &mut self.clipboard,
+            ClipboardType::Primary => &mut self.primary,
+        }
+    }
+
+    /// Whether `clipboard_type` changes going `way` are forwarded.
+    pub fn allows(&self, clipboard_type: &ClipboardType, way: Way) -> bool {
+        self.direction(clipboard_type).allows(way)
+    }
+}
+
+/// Sync of a selection into another one of the other side, e.g. the X11
+/// primary selection into the Wayland clipboard.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    pub from: ClipboardType,
+    pub to: ClipboardType,
+    /// Which sides `from` is synced from, both by default.
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Forwards the clipboard changes made by other clients of each backend to
+/// the other one.
+///
+/// Backends don't report the selections they set themselves, so content
+/// never bounces back and forth, while copying the same content again on
+/// one side still takes the selection over on the other.
+pub struct SyncEngine {
+    backends: Option<(Box<dyn ClipboardBackend>, Box<dyn ClipboardBackend>)>,
+    config: watch::Receiver<Config>,
+    stop_tx: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl SyncEngine {
+    /// Creates an engine bridging `first` and `second`, e.g. X11 and Wayland.
+    pub fn new(first: impl ClipboardBackend, second: impl ClipboardBackend) -> Self {
+        Self {
+            backends: Some((Box::new(first), Box::new(second))),
+            config: watch::channel(Config::default()).1,
+            stop_tx: None,
+            task: None,
+        }
+    }
+
+    /// Follows `config` instead of the defaults, including the versions it
+    /// is updated to while the engine runs.
+    pub fn config(mut self, config: watch::Receiver<Config>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Starts forwarding changes in a background task.
+    ///
+    /// Must be called inside a tokio runtime. Does nothing if the engine was
+    /// already started.
+    pub fn start(&mut self) {
+        let Some((mut first, mut second)) = self.backends.take() else {
+            debug!("[Sync] Sync engine already started");
+            return;
+        };
+        let (Some(mut first_changes), Some(mut second_changes)) =
+            (first.changes(), second.changes())
+        else {
+            error!("[Sync] Backend change streams were already taken");
+            return;
+        };
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let config = self.config.clone();
+
+        self.stop_tx = Some(stop_tx);
+        self.task = Some(tokio::spawn(async move {
+            info!(
+                "[Sync] Starting sync loop: {} <-> {}",
+                first.name(),
+                second.name()
+            );
+
+            loop {
+                tokio::select! {
+                    _ = &mut stop_rx => break,
+                    Some((content, clipboard_type)) = first_changes.recv() => {
+                        let way = Way::FirstToSecond;
+                        forward(&*first, &*second, way, &config.borrow(), content, clipboard_type);
+                    }
+                    Some((content, clipboard_type)) = second_changes.recv() => {
+                        let way = Way::SecondToFirst;
+                        forward(&*second, &*first, way, &config.borrow(), content, clipboard_type);
+                    }
+                    else => break,
+                }
+            }
+
+            // Dropping the backends closes their set clipboard channels, which
+            // stops the event loops of both sides
+            info!("[Sync] Sync loop stopped");
+        }));
+    }
+
+    /// Stops the sync loop and waits for it to finish.
+    pub async fn stop(&mut self) {
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+        if let Some(task) = self.task.take()
+            && let Err(e) = task.await
+        {
+            error!("[Sync] Sync task error: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendCapabilities, ChannelBackend, MemoryBackend, MemoryClipboard};
+    use crate::{TEXT_HTML_ATOM, UTF8_STRING_ATOM};
+
+    fn capabilities() -> BackendCapabilities {
+        BackendCapabilities {
+            selections: vec![ClipboardType::Clipboard, ClipboardType::Primary],
+            mime_types: None,
+        }
+    }
+
+    /// Starts an engine bridging two memory backends.
+    fn bridge(
+        wayland_selections: Vec<ClipboardType>,
+    ) -> (SyncEngine, MemoryClipboard, MemoryClipboard) {
+        let (x11, x11_clipboard) = MemoryBackend::new("X11", capabilities());
+        let (wayland, wayland_clipboard) = MemoryBackend::new(
+            "Wayland",
+            BackendCapabilities {
+                selections: wayland_selections,
+                mime_types: None,
+            },
+        );
+        let mut engine = SyncEngine::new(x11, wayland);
+        engine.start();
+        (engine, x11_clipboard, wayland_clipboard)
+    }
+
+    #[tokio::test]
+    async fn test_forwards_every_change_and_stops() {
+        let (x11, x11_endpoint) = ChannelBackend::new("X11", capabilities());
+        let (wayland, mut wayland_endpoint) = ChannelBackend::new("Wayland", capabilities());
+        let mut engine = SyncEngine::new(x11, wayland);
+        engine.start();
+
+        let content = ClipboardContent::text("hello");
+        for _ in 0..2 {
+            x11_endpoint
+                .sync_tx
+                .send((content.clone(), ClipboardType::Clipboard))
+                .unwrap();
+        }
+
+        for _ in 0..2 {
+            assert_eq!(
+                wayland_endpoint.set_clipboard_rx.recv().await,
+                Some((content.clone(), ClipboardType::Clipboard))
+            );
+        }
+
+        engine.stop().await;
+        assert_eq!(wayland_endpoint.set_clipboard_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_formats_the_target_cannot_take_are_dropped() {
+        let (x11, x11_clipboard) = MemoryBackend::new("X11", capabilities());
+        let (wayland, mut wayland_clipboard) = MemoryBackend::new(
+            "Wayland",
+            BackendCapabilities {
+                mime_types: Some(vec![UTF8_STRING_ATOM.to_string()]),
+                ..capabilities()
+            },
+        );
+        let mut engine = SyncEngine::new(x11, wayland);
+        engine.start();
+
+        // Nothing is left of HTML alone, text goes through under any alias
+        let mut html = ClipboardContent::Empty;
+        html.insert(TEXT_HTML_ATOM, b"<b>no</b>".to_vec());
+        x11_clipboard.copy(html, ClipboardType::Clipboard);
+        let mut both = ClipboardContent::text("ok");
+        both.insert(TEXT_HTML_ATOM, b"<b>ok</b>".to_vec());
+        x11_clipboard.copy(both, ClipboardType::Clipboard);
+
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((ClipboardContent::text("ok"), ClipboardType::Clipboard))
+        );
+        engine.stop().await;
+        assert_eq!(wayland_clipboard.try_next_write(), None);
+    }
+
+    #[tokio::test]
+    async fn test_identical_content_is_synced_again() {
+        let (mut engine, x11, mut wayland) = bridge(capabilities().selections);
+
+        // Copying the same content again takes the selection over again
+        x11.copy(ClipboardContent::text("one"), ClipboardType::Clipboard);
+        x11.copy(ClipboardContent::text("one"), ClipboardType::Clipboard);
+        x11.copy(ClipboardContent::text("two"), ClipboardType::Clipboard);
+
+        for _ in 0..2 {
+            assert_eq!(
+                wayland.next_write().await,
+                Some((ClipboardContent::text("one"), ClipboardType::Clipboard))
+            );
+        }
+        assert_eq!(
+            wayland.next_write().await,
+            Some((ClipboardContent::text("two"), ClipboardType::Clipboard))
+        );
+        engine.stop().await;
+        assert_eq!(wayland.try_next_write(), None);
+    }
+
+    #[tokio::test]
+    async fn test_empty_content_is_not_synced() {
+        let (mut engine, x11, mut wayland) = bridge(capabilities().selections);
+
+        x11.copy(ClipboardContent::text("one"), ClipboardType::Clipboard);
+        x11.copy(ClipboardContent::Empty, ClipboardType::Clipboard);
+        x11.copy(ClipboardContent::text("one"), ClipboardType::Clipboard);
+
+        for _ in 0..2 {
+            assert_eq!(
+                wayland.next_write().await,
+                Some((ClipboardContent::text("one"), ClipboardType::Clipboard))
+            );
+        }
+        engine.stop().await;
+        assert_eq!(wayland.try_next_write(), None);
+        assert_eq!(
+            wayland.paste(&ClipboardType::Clipboard),
+            Some(ClipboardContent::text("one"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_selections_are_routed_separately() {
+        let (mut engine, x11, mut wayland) = bridge(capabilities().selections);
+
+        x11.copy(ClipboardContent::text("same"), ClipboardType::Primary);
+        x11.copy(ClipboardContent::text("same"), ClipboardType::Clipboard);
+
+        assert_eq!(
+            wayland.next_write().await,
+            Some((ClipboardContent::text("same"), ClipboardType::Primary))
+        );
+        assert_eq!(
+            wayland.next_write().await,
+            Some((ClipboardContent::text("same"), ClipboardType::Clipboard))
+        );
+        engine.stop().await;
+        assert_eq!(
+            wayland.paste(&ClipboardType::Primary),
+            Some(ClipboardContent::text("same"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_alternating_copies_are_synced_once_each() {
+        let (mut engine, mut x11, mut wayland) = bridge(capabilities().selections);
+
+        for text in ["a", "b", "a", "b"] {
+            x11.copy(ClipboardContent::text(text), ClipboardType::Clipboard);
+            assert_eq!(
+                wayland.next_write().await,
+                Some((ClipboardContent::text(text), ClipboardType::Clipboard))
+            );
+            wayland.copy(ClipboardContent::text(text), ClipboardType::Clipboard);
+            assert_eq!(
+                x11.next_write().await,
+                Some((ClipboardContent::text(text), ClipboardType::Clipboard))
+            );
+        }
+        engine.stop().await;
+        assert_eq!(x11.try_next_write(), None);
+        assert_eq!(wayland.try_next_write(), None);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_selection_is_skipped() {
+        let (mut engine, x11, mut wayland) = bridge(vec![ClipboardType::Clipboard]);
+
+        x11.copy(ClipboardContent::text("primary"), ClipboardType::Primary);
+        x11.copy(
+            ClipboardContent::text("clipboard"),
+            ClipboardType::Clipboard,
+        );
+
+        assert_eq!(
+            wayland.next_write().await,
+            Some((
+                ClipboardContent::text("clipboard"),
+                ClipboardType::Clipboard
+            ))
+        );
+        engine.stop().await;
+        assert_eq!(wayland.try_next_write(), None);
+        assert_eq!(wayland.paste(&ClipboardType::Primary), None);
+    }
+
+    #[tokio::test]
+    async fn test_policy_limits_what_is_synced() {
+        let (x11, mut x11_clipboard) = MemoryBackend::new("X11", capabilities());
+        let (wayland, mut wayland_clipboard) = MemoryBackend::new("Wayland", capabilities());
+        let config = Config {
+            sync: Policy {
+                clipboard: Direction::Both,
+                primary: Direction::FirstToSecond,
+            },
+            ..Config::default()
+        };
+        let mut engine = SyncEngine::new(x11, wayland).config(watch::channel(config).1);
+        engine.start();
+
+        wayland_clipboard.copy(ClipboardContent::text("wayland"), ClipboardType::Primary);
+        x11_clipboard.copy(ClipboardContent::text("x11"), ClipboardType::Primary);
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((ClipboardContent::text("x11"), ClipboardType::Primary))
+        );
+        wayland_clipboard.copy(ClipboardContent::text("wayland"), ClipboardType::Clipboard);
+        assert_eq!(
+            x11_clipboard.next_write().await,
+            Some((ClipboardContent::text("wayland"), ClipboardType::Clipboard))
+        );
+        engine.stop().await;
+        assert_eq!(wayland_clipboard.try_next_write(), None);
+        assert_eq!(x11_clipboard.try_next_write(), None);
+    }
+
+    #[tokio::test]
+    async fn test_mapped_selections_are_synced() {
+        let (x11, mut x11_clipboard) = MemoryBackend::new("X11", capabilities());
+        let (wayland, mut wayland_clipboard) = MemoryBackend::new("Wayland", capabilities());
+        let config = Config {
+            mappings: vec![
+                Mapping {
+                    from: ClipboardType::Primary,
+                    to: ClipboardType::Clipboard,
+                    direction: Direction::FirstToSecond,
+                },
+                Mapping {
+                    from: ClipboardType::Clipboard,
+                    to: ClipboardType::Primary,
+                    direction: Direction::SecondToFirst,
+                },
+            ],
+            ..Config::default()
+        };
+        let mut engine = SyncEngine::new(x11, wayland).config(watch::channel(config).1);
+        engine.start();
+
+        let selected = ClipboardContent::text("selected");
+        x11_clipboard.copy(selected.clone(), ClipboardType::Primary);
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((selected.clone(), ClipboardType::Primary))
+        );
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((selected.clone(), ClipboardType::Clipboard))
+        );
+
+        let copied = ClipboardContent::text("copied");
+        wayland_clipboard.copy(copied.clone(), ClipboardType::Clipboard);
+        assert_eq!(
+            x11_clipboard.next_write().await,
+            Some((copied.clone(), ClipboardType::Clipboard))
+        );
+        assert_eq!(
+            x11_clipboard.next_write().await,
+            Some((copied, ClipboardType::Primary))
+        );
+
+        engine.stop().await;
+        assert_eq!(wayland_clipboard.try_next_write(), None);
+        assert_eq!(x11_clipboard.try_next_write(), None);
+    }
+
+    #[test]
+    fn test_off_allows_nothing_and_both_allows_either_way() {
+        let policy = Policy {
+            clipboard: Direction::Both,
+            primary: Direction::Off,
+        };
+        for way in [Way::FirstToSecond, Way::SecondToFirst] {
+            assert!(policy.allows(&ClipboardType::Clipboard, way));
+            assert!(!policy.allows(&ClipboardType::Primary, way));
+        }
+        assert!(Direction::FirstToSecond.allows(Way::FirstToSecond));
+        assert!(!Direction::SecondToFirst.allows(Way::FirstToSecond));
+    }
+
+    #[tokio::test]
+    async fn test_config_changes_apply_to_the_running_engine() {
+        let (x11, x11_clipboard) = MemoryBackend::new("X11", capabilities());
+        let (wayland, mut wayland_clipboard) = MemoryBackend::new("Wayland", capabilities());
+        let (config_tx, config_rx) = watch::channel(Config::default());
+        let mut engine = SyncEngine::new(x11, wayland).config(config_rx);
+        engine.start();
+
+        let mut html = ClipboardContent::text("text");
+        html.insert(TEXT_HTML_ATOM, b"<b>text</b>".to_vec());
+        x11_clipboard.copy(html.clone(), ClipboardType::Clipboard);
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((html, ClipboardType::Clipboard))
+        );
+
+        config_tx.send_modify(|config| {
+            config.limits.max_size = Some(4);
+            config.mime_types.exclude = vec![TEXT_HTML_ATOM.to_string()];
+        });
+        x11_clipboard.copy(ClipboardContent::text("too long"), ClipboardType::Clipboard);
+        let mut html = ClipboardContent::text("ok");
+        html.insert(TEXT_HTML_ATOM, b"<b>ok</b>".to_vec());
+        x11_clipboard.copy(html, ClipboardType::Clipboard);
+
+        assert_eq!(
+            wayland_clipboard.next_write().await,
+            Some((ClipboardContent::text("ok"), ClipboardType::Clipboard))
+        );
+        engine.stop().await;
+        assert_eq!(wayland_clipboard.try_next_write(), None);
+    }
+}
@@ -1,17 +1,31 @@
 use std::fs::File;
-use std::os::fd::AsFd;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
+use nix::fcntl::{FcntlArg, OFlag, fcntl};
 use nix::unistd;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
 use tokio::sync::Mutex;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time;
 use tracing::{debug, error, info, warn};
 use wayland_client::{
-    Connection, Dispatch, QueueHandle, event_created_child,
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+    backend::WaylandError,
+    event_created_child,
     protocol::{wl_compositor, wl_registry, wl_seat},
 };
+use wayland_protocols::ext::data_control::v1::client::{
+    ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+    ext_data_control_manager_v1::{self, ExtDataControlManagerV1},
+    ext_data_control_offer_v1::{self, ExtDataControlOfferV1},
+    ext_data_control_source_v1::{self, ExtDataControlSourceV1},
+};
 use wayland_protocols::wp::primary_selection::zv1::client::{
     zwp_primary_selection_device_manager_v1::ZwpPrimarySelectionDeviceManagerV1,
     zwp_primary_selection_offer_v1::{self, ZwpPrimarySelectionOfferV1},
@@ -19,12 +33,45 @@ use wayland_protocols::wp::primary_selection::zv1::client::{
 };
 use wayland_protocols_wlr::data_control::v1::client::{
     zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
-    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_manager_v1::{self, ZwlrDataControlManagerV1},
     zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
     zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
 };
 
-use crate::{ClipboardContent, ClipboardType, SyncEvent};
+use crate::backend::BackendCapabilities;
+use crate::config::Config;
+use crate::error::ResultExt;
+use crate::reconnect::Backoff;
+use crate::{
+    ClipboardContent, ClipboardType, Error, STRING_ATOM, TEXT_MIME_TYPES, TEXT_PLAIN_UTF8_ATOM,
+    convert, is_text_mime, origin_mime_type,
+};
+
+// ============================================================================
+// Connection
+// ============================================================================
+
+/// Connects to the compositor named `display`, or to `$WAYLAND_DISPLAY` if
+/// `None`.
+///
+/// Like `$WAYLAND_DISPLAY`, `display` is either a socket name in
+/// `$XDG_RUNTIME_DIR` or an absolute path.
+pub fn connect(display: Option<&str>) -> Result<Connection, Error> {
+    let Some(display) = display else {
+        return Ok(Connection::connect_to_env()?);
+    };
+
+    let mut path = PathBuf::from(display);
+    if path.is_relative() {
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .ok_or_else(|| Error::ConnectionLost("XDG_RUNTIME_DIR is not set".to_string()))?;
+        path = PathBuf::from(runtime_dir).join(path);
+    }
+    let stream = UnixStream::connect(&path).map_err(|e| {
+        Error::ConnectionLost(format!("Failed to connect to {}: {}", path.display(), e))
+    })?;
+    Ok(Connection::from_socket(stream)?)
+}
 
 // ============================================================================
 // Wayland State
@@ -33,28 +80,37 @@ use crate::{ClipboardContent, ClipboardType, SyncEvent};
 #[derive(Debug, Clone, Copy)]
 pub struct GlobalData;
 
+/// MIME types advertised by a data offer, collected from its `offer` events.
+#[derive(Debug, Default)]
+pub struct OfferData {
+    mime_types: std::sync::Mutex<Vec<String>>,
+}
+
+impl OfferData {
+    pub fn mime_types(&self) -> Vec<String> {
+        self.mime_types.lock().unwrap().clone()
+    }
+}
+
 pub struct WaylandState {
     _qh: QueueHandle<Self>,
-    sync_tx: mpsc::UnboundedSender<SyncEvent>,
-    data_control_manager: Option<ZwlrDataControlManagerV1>,
-    data_control_device: Option<ZwlrDataControlDeviceV1>,
+    sync_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    data_control_manager: Option<DataControlManager>,
+    data_control_device: Option<DataControlDevice>,
     primary_selection_manager: Option<ZwpPrimarySelectionDeviceManagerV1>,
     compositor: Option<wl_compositor::WlCompositor>,
     seat: Option<wl_seat::WlSeat>,
-    clipboard_content: Arc<Mutex<Option<String>>>,
-    primary_content: Arc<Mutex<Option<String>>>,
-    clipboard_source: Option<ZwlrDataControlSourceV1>,
-    primary_source: Option<ZwlrDataControlSourceV1>,
-    _set_clipboard_tx: mpsc::UnboundedSender<(String, ClipboardType)>,
-    // Store content to be written when requested
-    pending_primary_content: Arc<Mutex<Option<String>>>,
+    clipboard_content: Arc<Mutex<Option<ClipboardContent>>>,
+    primary_content: Arc<Mutex<Option<ClipboardContent>>>,
+    clipboard_source: Option<DataControlSource>,
+    primary_source: Option<DataControlSource>,
+    config: watch::Receiver<Config>,
 }
 
 impl WaylandState {
     pub fn new(
         qh: QueueHandle<Self>,
-        sync_tx: mpsc::UnboundedSender<SyncEvent>,
-        set_clipboard_tx: mpsc::UnboundedSender<(String, ClipboardType)>,
+        sync_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
     ) -> Self {
         Self {
             _qh: qh,
@@ -68,12 +124,35 @@ impl WaylandState {
             primary_content: Arc::new(Mutex::new(None)),
             clipboard_source: None,
             primary_source: None,
-            _set_clipboard_tx: set_clipboard_tx,
-            pending_primary_content: Arc::new(Mutex::new(None)),
+            config: watch::channel(Config::default()).1,
+        }
+    }
+
+    /// Follows `config` for timeouts and synced formats, including the
+    /// versions it is updated to while the event loop runs.
+    pub fn set_config(&mut self, config: watch::Receiver<Config>) {
+        self.config = config;
+    }
+
+    /// Time clients have to read or write our selection pipes.
+    fn pipe_timeout(&self) -> Duration {
+        self.config.borrow().timeouts.pipe
+    }
+
+    /// Selections the Wayland side of the bridge can exchange. Sources may
+    /// offer any MIME type, so none is ruled out.
+    pub fn capabilities() -> BackendCapabilities {
+        BackendCapabilities {
+            selections: vec![ClipboardType::Clipboard, ClipboardType::Primary],
+            mime_types: None,
         }
     }
 
-    pub fn set_clipboard_content(&mut self, content: String, clipboard_type: ClipboardType) {
+    pub fn set_clipboard_content(
+        &mut self,
+        content: ClipboardContent,
+        clipboard_type: ClipboardType,
+    ) {
         info!(
             "[Wayland] Setting clipboard content: type={:?}, len={}",
             clipboard_type,
@@ -90,22 +169,19 @@ impl WaylandState {
         match clipboard_type {
             ClipboardType::Clipboard => {
                 // Store content first, before creating source
-                // *self.pending_clipboard_content.blocking_lock() = Some(content.clone());
                 *self.clipboard_content.blocking_lock() = Some(content.clone());
 
                 // Create new source BEFORE destroying old one to avoid gap
                 if let Some(manager) = &self.data_control_manager {
-                    let source = manager.create_data_source(&self._qh, ());
-                    source.offer("text/plain;charset=utf-8".into());
-                    source.offer("text/plain".into());
-                    source.offer("UTF8_STRING".into());
-                    source.offer("TEXT".into());
-                    source.offer("STRING".into());
+                    let source = manager.create_data_source(&self._qh);
+                    for mime_type in offered_mime_types(&content) {
+                        source.offer(mime_type);
+                    }
 
                     debug!("[Wayland] Created clipboard source: {:?}", source);
 
                     // Set selection FIRST - this makes the new source active
-                    device.set_selection(Some(&source));
+                    device.set_selection(&source);
                     debug!("[Wayland] Set clipboard selection");
 
                     // Now destroy old source after new one is active
@@ -125,22 +201,19 @@ impl WaylandState {
             }
             ClipboardType::Primary => {
                 // Store content first, before creating source
-                *self.pending_primary_content.blocking_lock() = Some(content.clone());
                 *self.primary_content.blocking_lock() = Some(content.clone());
 
                 // Create new source BEFORE destroying old one to avoid gap
                 if let Some(manager) = &self.data_control_manager {
-                    let source = manager.create_data_source(&self._qh, ());
-                    source.offer("text/plain;charset=utf-8".into());
-                    source.offer("text/plain".into());
-                    source.offer("UTF8_STRING".into());
-                    source.offer("TEXT".into());
-                    source.offer("STRING".into());
+                    let source = manager.create_data_source(&self._qh);
+                    for mime_type in offered_mime_types(&content) {
+                        source.offer(mime_type);
+                    }
 
                     debug!("[Wayland] Created primary source: {:?}", source);
 
                     // Set selection FIRST - this makes the new source active
-                    device.set_primary_selection(Some(&source));
+                    device.set_primary_selection(&source);
                     debug!("[Wayland] Set primary selection");
 
                     // Now destroy old source after new one is active
@@ -158,6 +231,708 @@ impl WaylandState {
             }
         }
     }
+
+    fn handle_selection(&self, offer: Option<DataControlOffer>, clipboard_type: ClipboardType) {
+        info!(
+            "[Wayland] {:?} selection changed: {:?}",
+            clipboard_type, offer
+        );
+        match &offer {
+            // Setting a selection makes the compositor offer our own source back to us
+            Some(offer) if offer.mime_types().iter().any(|m| m == origin_mime_type()) => {
+                debug!(
+                    "[Wayland] {:?} selection was set by us, ignoring",
+                    clipboard_type
+                );
+            }
+            Some(offer) => self.receive_offer(offer, clipboard_type),
+            // Wechat sends empty clipboard content to wayland,
+            // clear the selection will trigger recursive call to this function.
+            // A cleared primary selection likewise keeps the X11 side as is.
+            None => debug!("[Wayland] {:?} selection cleared, ignoring", clipboard_type),
+        }
+        // The source writes to the pipes we handed over without the offer
+        if let Some(offer) = offer {
+            offer.destroy();
+        }
+    }
+
+    /// Reads every synced format of `offer` and sends the content to X11.
+    fn receive_offer(&self, offer: &DataControlOffer, clipboard_type: ClipboardType) {
+        let offered = offer.mime_types();
+        let mime_types = negotiate_mime_types(&offered, &self.config.borrow().mime_types.sync);
+        if mime_types.is_empty() {
+            debug!(
+                "[Wayland] {:?} offer has no supported MIME type, skipping: {:?}",
+                clipboard_type, offered
+            );
+            return;
+        }
+        debug!(
+            "[Wayland] Reading {:?} as {:?} (offered: {:?})",
+            clipboard_type, mime_types, offered
+        );
+
+        // Create pipes for receiving data
+        let mut pipes = Vec::new();
+        for mime_type in mime_types {
+            match unistd::pipe() {
+                Ok((read_fd, write_fd)) => {
+                    debug!("[Wayland] Created pipe for reading {}", mime_type);
+                    offer.receive(&mime_type, write_fd.as_fd());
+                    // Close the write end immediately after receive() - this signals EOF to the reader
+                    // The compositor has already duplicated the fd, so it's safe to close
+                    let _ = unistd::close(write_fd);
+                    pipes.push((mime_type, File::from(read_fd)));
+                }
+                Err(e) => {
+                    error!("[Wayland] Failed to create pipe: {}", e);
+                }
+            }
+        }
+
+        // Read from pipes in a separate task
+        let sync_tx = self.sync_tx.clone();
+        let timeout = self.pipe_timeout();
+        let max_size = self.config.borrow().limits.max_size;
+        tokio::task::spawn(async move {
+            let mut formats: Vec<(String, Vec<u8>)> = Vec::new();
+            for (mime_type, read_file) in pipes {
+                // The limit covers all formats together
+                let read = formats.iter().map(|(_, data)| data.len()).sum::<usize>();
+                let limit = max_size.map(|max_size| max_size.saturating_sub(read));
+                let data = match read_pipe(read_file, timeout, limit).await {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                        info!(
+                            "[Wayland] {:?} content exceeds {} bytes, skipping",
+                            clipboard_type,
+                            max_size.unwrap_or_default()
+                        );
+                        return;
+                    }
+                    // Partial content would be synced as if it were complete
+                    Err(e) => {
+                        warn!(
+                            "[Wayland] Failed to read {} from {:?} pipe: {}",
+                            mime_type, clipboard_type, e
+                        );
+                        continue;
+                    }
+                };
+                debug!(
+                    "[Wayland] Read {} bytes of {} from {:?} pipe",
+                    data.len(),
+                    mime_type,
+                    clipboard_type
+                );
+                if is_text_mime(&mime_type) {
+                    let Some(text) = decode_text(&mime_type, data) else {
+                        warn!("[Wayland] Failed to decode {:?} as UTF-8", clipboard_type);
+                        continue;
+                    };
+                    formats.push((TEXT_PLAIN_UTF8_ATOM.to_string(), text.into_bytes()));
+                } else {
+                    formats.push((mime_type, data));
+                }
+            }
+
+            let content = convert::complete_formats(ClipboardContent::from_formats(formats));
+            if content.is_empty() {
+                // Wechat sends empty clipboard content to wayland,
+                // however it uses x11 clipboard to send the actual content.
+                // So we ignore empty clipboard content.
+                warn!("[Wayland] Received empty {:?} content", clipboard_type);
+                return;
+            }
+
+            info!(
+                "[Wayland] {:?} content received: {:?}",
+                clipboard_type, content
+            );
+            let _ = sync_tx.send((content, clipboard_type));
+        });
+    }
+
+    /// Uses `manager` for the data control protocol, replacing a less preferred one.
+    fn set_data_control_manager(&mut self, manager: DataControlManager, qh: &QueueHandle<Self>) {
+        if let Some(device) = self.data_control_device.take() {
+            device.destroy();
+        }
+        for source in [self.clipboard_source.take(), self.primary_source.take()]
+            .into_iter()
+            .flatten()
+        {
+            source.destroy();
+        }
+        if let Some(old_manager) = self.data_control_manager.replace(manager) {
+            old_manager.destroy();
+        }
+        self.bind_data_control_device(qh);
+    }
+
+    /// Creates the data control device once both the manager and the seat are bound.
+    fn bind_data_control_device(&mut self, qh: &QueueHandle<Self>) {
+        if self.data_control_device.is_some() {
+            return;
+        }
+        let (Some(manager), Some(seat)) = (&self.data_control_manager, &self.seat) else {
+            debug!("[Wayland] Data control manager or seat not available yet");
+            return;
+        };
+        self.data_control_device = Some(manager.get_data_device(seat, qh));
+        info!(
+            "[Wayland] Data control device bound using {}",
+            manager.interface_name()
+        );
+    }
+
+    /// Writes the content held by `source` for `mime_type` to `fd`.
+    fn send_content(&self, source: &DataControlSource, mime_type: String, fd: OwnedFd) {
+        info!(
+            "[Wayland] Send data for mime type: {} from source: {:?}",
+            mime_type, source
+        );
+        if mime_type == origin_mime_type() {
+            debug!("[Wayland] The origin format has no data");
+            return;
+        }
+
+        // Determine which content to send based on source
+        let content = if Some(source) == self.clipboard_source.as_ref() {
+            debug!("[Wayland] This is clipboard source");
+            self.clipboard_content.blocking_lock().clone()
+        } else if Some(source) == self.primary_source.as_ref() {
+            debug!("[Wayland] This is primary source");
+            self.primary_content.blocking_lock().clone()
+        } else {
+            warn!(
+                "[Wayland] Unknown source {:?}, cannot determine content. Current clipboard: {:?}, Primary: {:?}",
+                source, self.clipboard_source, self.primary_source
+            );
+            // OwnedFd will be closed automatically when dropped
+            return;
+        };
+
+        if let Some(data) = content.as_ref().and_then(|content| content.get(&mime_type)) {
+            debug!(
+                "[Wayland] Writing {} bytes of {} to fd",
+                data.len(),
+                mime_type
+            );
+            spawn_write_pipe(fd, data.to_vec(), self.pipe_timeout());
+        } else {
+            warn!("[Wayland] No content available to send");
+            // OwnedFd will be closed automatically when dropped
+        }
+    }
+
+    /// Runs the Wayland event loop until the command channel is closed.
+    ///
+    /// Must be called from a blocking thread inside a tokio runtime (e.g.
+    /// `spawn_blocking`). The loop sleeps until either the Wayland socket
+    /// becomes readable or a command arrives, so it is fully idle otherwise.
+    pub fn run_event_loop(
+        &mut self,
+        conn: &Connection,
+        event_queue: &mut EventQueue<Self>,
+        mut set_clipboard_rx: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    ) -> Result<(), Error> {
+        self.event_loop(conn, event_queue, &mut set_clipboard_rx)
+    }
+
+    /// Runs the event loop, reconnecting through `connect` with backoff
+    /// whenever the compositor goes away.
+    ///
+    /// Same requirements as [`run_event_loop`](Self::run_event_loop). After
+    /// reconnecting, the selections we had set are set again, and the
+    /// compositor's current selections are read as usual.
+    pub fn run_with_reconnect(
+        &mut self,
+        mut conn: Connection,
+        mut event_queue: EventQueue<Self>,
+        mut set_clipboard_rx: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+        mut connect: impl FnMut() -> Result<Connection, Error>,
+    ) -> Result<(), Error> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| {
+            Error::Runtime(format!(
+                "Wayland event loop requires a tokio runtime: {}",
+                e
+            ))
+        })?;
+        let _guard = runtime.enter();
+        let mut backoff = Backoff::default();
+
+        loop {
+            match self.event_loop(&conn, &mut event_queue, &mut set_clipboard_rx) {
+                Err(e) if e.is_connection_lost() => warn!("[Wayland] {}, reconnecting", e),
+                result => return result,
+            }
+
+            let mut owned = self.owned_selections();
+            loop {
+                // Keep taking commands while disconnected, so the latest
+                // content is the one set once reconnected
+                let deadline = tokio::time::Instant::now() + backoff.next_delay();
+                while let Some(command) = runtime.block_on(async {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => None,
+                        command = set_clipboard_rx.recv() => Some(command),
+                    }
+                }) {
+                    let Some((content, clipboard_type)) = command else {
+                        info!("[Wayland] Set clipboard channel closed while reconnecting");
+                        return Ok(());
+                    };
+                    *self.selection_content(&clipboard_type).blocking_lock() = Some(content);
+                    if !owned.contains(&clipboard_type) {
+                        owned.push(clipboard_type);
+                    }
+                }
+
+                match connect().and_then(|new_conn| {
+                    let new_queue = self.reconnect(&new_conn)?;
+                    Ok((new_conn, new_queue))
+                }) {
+                    Ok((new_conn, new_queue)) => {
+                        conn = new_conn;
+                        event_queue = new_queue;
+                        break;
+                    }
+                    Err(e) => warn!("[Wayland] Failed to reconnect: {}", e),
+                }
+            }
+            backoff.reset();
+
+            for clipboard_type in owned {
+                let content = self
+                    .selection_content(&clipboard_type)
+                    .blocking_lock()
+                    .clone();
+                if let Some(content) = content {
+                    info!(
+                        "[Wayland] Setting {:?} again after reconnecting",
+                        clipboard_type
+                    );
+                    self.set_clipboard_content(content, clipboard_type);
+                }
+            }
+        }
+    }
+
+    /// Binds the globals of a new connection, forgetting the proxies of the
+    /// old one.
+    fn reconnect(&mut self, conn: &Connection) -> Result<EventQueue<Self>, Error> {
+        let mut event_queue = conn.new_event_queue();
+        let qh = event_queue.handle();
+
+        self._qh = qh.clone();
+        self.data_control_manager = None;
+        self.data_control_device = None;
+        self.primary_selection_manager = None;
+        self.compositor = None;
+        self.seat = None;
+        self.clipboard_source = None;
+        self.primary_source = None;
+
+        conn.display().get_registry(&qh, GlobalData);
+        event_queue
+            .roundtrip(self)
+            .context("Failed to initialize globals")?;
+        if self.data_control_manager.is_none() {
+            return Err(Error::MissingProtocol(
+                "compositor offers no data control manager yet".to_string(),
+            ));
+        }
+
+        info!("[Wayland] Reconnected");
+        Ok(event_queue)
+    }
+
+    /// Selections currently set to one of our sources.
+    fn owned_selections(&self) -> Vec<ClipboardType> {
+        let mut owned = Vec::new();
+        if self.clipboard_source.is_some() {
+            owned.push(ClipboardType::Clipboard);
+        }
+        if self.primary_source.is_some() {
+            owned.push(ClipboardType::Primary);
+        }
+        owned
+    }
+
+    fn selection_content(
+        &self,
+        clipboard_type: &ClipboardType,
+    ) -> &Arc<Mutex<Option<ClipboardContent>>> {
+        match clipboard_type {
+            ClipboardType::Clipboard => &self.clipboard_content,
+            ClipboardType::Primary => &self.primary_content,
+        }
+    }
+
+    /// Forgets `source` once another client replaced it.
+    fn source_cancelled(&mut self, source: &DataControlSource) {
+        debug!("[Wayland] Data source cancelled");
+        if self.clipboard_source.as_ref() == Some(source) {
+            self.clipboard_source = None;
+        } else if self.primary_source.as_ref() == Some(source) {
+            self.primary_source = None;
+        }
+        source.destroy();
+    }
+
+    fn event_loop(
+        &mut self,
+        conn: &Connection,
+        event_queue: &mut EventQueue<Self>,
+        set_clipboard_rx: &mut mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    ) -> Result<(), Error> {
+        info!("[Wayland] Starting event loop");
+
+        if self.data_control_manager.is_none() {
+            return Err(Error::MissingProtocol(
+                "compositor offers neither ext_data_control_manager_v1 nor zwlr_data_control_manager_v1"
+                    .to_string(),
+            ));
+        }
+
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| {
+            Error::Runtime(format!(
+                "Wayland event loop requires a tokio runtime: {}",
+                e
+            ))
+        })?;
+        let _guard = runtime.enter();
+        let backend = conn.backend();
+        let wayland_fd = AsyncFd::with_interest(backend.poll_fd().as_raw_fd(), Interest::READABLE)
+            .map_err(|e| {
+                Error::ConnectionLost(format!("Failed to watch Wayland connection: {}", e))
+            })?;
+
+        loop {
+            // Dispatch everything already read from the socket
+            event_queue
+                .dispatch_pending(self)
+                .context("Failed to dispatch events")?;
+
+            // Flush any pending requests
+            event_queue.flush().context("Failed to flush connection")?;
+
+            // Events queued by another reader must be dispatched before we may read
+            let Some(read_guard) = event_queue.prepare_read() else {
+                continue;
+            };
+
+            // Wait for the compositor or a set clipboard request
+            let ready = runtime.block_on(async {
+                tokio::select! {
+                    guard = wayland_fd.readable() => Ok(guard),
+                    command = set_clipboard_rx.recv() => Err(command),
+                }
+            });
+
+            match ready {
+                Ok(Ok(mut ready_guard)) => {
+                    // Readiness is only cleared once the socket is drained
+                    let result = ready_guard.try_io(|_| match read_guard.read() {
+                        Err(WaylandError::Io(e)) => Err(e),
+                        other => Ok(other),
+                    });
+                    match result {
+                        Ok(Ok(Ok(_))) | Err(_) => {}
+                        Ok(Ok(Err(e))) => {
+                            return Err(Error::from(e).context("Failed to read events"));
+                        }
+                        Ok(Err(e)) => {
+                            return Err(Error::ConnectionLost(format!(
+                                "Failed to read events: {}",
+                                e
+                            )));
+                        }
+                    }
+                }
+                Ok(Err(e)) => {
+                    return Err(Error::ConnectionLost(format!(
+                        "Failed to poll Wayland connection: {}",
+                        e
+                    )));
+                }
+                Err(Some((content, clipboard_type))) => {
+                    drop(read_guard);
+                    self.set_clipboard_content(content, clipboard_type);
+                }
+                Err(None) => {
+                    info!("[Wayland] Set clipboard channel closed, stopping event loop");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Data Control Protocols
+// ============================================================================
+//
+// ext-data-control-v1 is the standardized successor of zwlr_data_control_v1.
+// Both have the same requests and events, so the rest of the state only deals
+// with these wrappers and doesn't care which one the compositor offers.
+
+/// A data control manager, preferring ext-data-control-v1 over the wlr protocol.
+#[derive(Debug, Clone)]
+enum DataControlManager {
+    Ext(ExtDataControlManagerV1),
+    Wlr(ZwlrDataControlManagerV1),
+}
+
+impl DataControlManager {
+    fn interface_name(&self) -> &'static str {
+        match self {
+            Self::Ext(_) => "ext_data_control_manager_v1",
+            Self::Wlr(_) => "zwlr_data_control_manager_v1",
+        }
+    }
+
+    fn create_data_source(&self, qh: &QueueHandle<WaylandState>) -> DataControlSource {
+        match self {
+            Self::Ext(manager) => DataControlSource::Ext(manager.create_data_source(qh, ())),
+            Self::Wlr(manager) => DataControlSource::Wlr(manager.create_data_source(qh, ())),
+        }
+    }
+
+    fn get_data_device(
+        &self,
+        seat: &wl_seat::WlSeat,
+        qh: &QueueHandle<WaylandState>,
+    ) -> DataControlDevice {
+        match self {
+            Self::Ext(manager) => DataControlDevice::Ext(manager.get_data_device(seat, qh, ())),
+            Self::Wlr(manager) => DataControlDevice::Wlr(manager.get_data_device(seat, qh, ())),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Self::Ext(manager) => manager.destroy(),
+            Self::Wlr(manager) => manager.destroy(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DataControlDevice {
+    Ext(ExtDataControlDeviceV1),
+    Wlr(ZwlrDataControlDeviceV1),
+}
+
+impl DataControlDevice {
+    fn set_selection(&self, source: &DataControlSource) {
+        match (self, source) {
+            (Self::Ext(device), DataControlSource::Ext(source)) => {
+                device.set_selection(Some(source))
+            }
+            (Self::Wlr(device), DataControlSource::Wlr(source)) => {
+                device.set_selection(Some(source))
+            }
+            _ => warn!("[Wayland] Data source and device use different protocols"),
+        }
+    }
+
+    fn set_primary_selection(&self, source: &DataControlSource) {
+        match (self, source) {
+            (Self::Ext(device), DataControlSource::Ext(source)) => {
+                device.set_primary_selection(Some(source))
+            }
+            (Self::Wlr(device), DataControlSource::Wlr(source)) => {
+                device.set_primary_selection(Some(source))
+            }
+            _ => warn!("[Wayland] Data source and device use different protocols"),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Self::Ext(device) => device.destroy(),
+            Self::Wlr(device) => device.destroy(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DataControlSource {
+    Ext(ExtDataControlSourceV1),
+    Wlr(ZwlrDataControlSourceV1),
+}
+
+impl DataControlSource {
+    fn offer(&self, mime_type: String) {
+        match self {
+            Self::Ext(source) => source.offer(mime_type),
+            Self::Wlr(source) => source.offer(mime_type),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Self::Ext(source) => source.destroy(),
+            Self::Wlr(source) => source.destroy(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum DataControlOffer {
+    Ext(ExtDataControlOfferV1),
+    Wlr(ZwlrDataControlOfferV1),
+}
+
+impl DataControlOffer {
+    /// MIME types the source advertised for this offer.
+    fn mime_types(&self) -> Vec<String> {
+        let data = match self {
+            Self::Ext(offer) => offer.data::<OfferData>(),
+            Self::Wlr(offer) => offer.data::<OfferData>(),
+        };
+        data.map(OfferData::mime_types).unwrap_or_default()
+    }
+
+    fn receive(&self, mime_type: &str, fd: std::os::fd::BorrowedFd) {
+        match self {
+            Self::Ext(offer) => offer.receive(mime_type.to_string(), fd),
+            Self::Wlr(offer) => offer.receive(mime_type.to_string(), fd),
+        }
+    }
+
+    fn destroy(&self) {
+        match self {
+            Self::Ext(offer) => offer.destroy(),
+            Self::Wlr(offer) => offer.destroy(),
+        }
+    }
+}
+
+/// Writes `data` to a selection pipe in a separate task, so a slow reader
+/// can't stall the event loop. The pipe is closed once everything is written.
+fn spawn_write_pipe(fd: OwnedFd, data: Vec<u8>, timeout: Duration) {
+    tokio::task::spawn(async move {
+        match write_pipe(fd, &data, timeout).await {
+            Ok(()) => debug!("[Wayland] Successfully wrote {} bytes", data.len()),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                debug!("[Wayland] Reader closed the pipe before all data was written")
+            }
+            Err(e) => error!("[Wayland] Failed to write data: {}", e),
+        }
+    });
+}
+
+/// Writes all of `data` to a selection pipe, giving up if the reader stalls
+/// for `timeout`.
+async fn write_pipe(fd: OwnedFd, data: &[u8], timeout: Duration) -> io::Result<()> {
+    let flags = fcntl(&fd, FcntlArg::F_GETFL)?;
+    fcntl(
+        &fd,
+        FcntlArg::F_SETFL(OFlag::from_bits_retain(flags) | OFlag::O_NONBLOCK),
+    )?;
+    let writer = AsyncFd::with_interest(fd, Interest::WRITABLE)?;
+
+    let mut written = 0;
+    while written < data.len() {
+        let mut guard = time::timeout(timeout, writer.writable())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "pipe write timed out"))??;
+        // EAGAIN clears the readiness and waits for the reader again
+        match guard.try_io(|fd| Ok(unistd::write(fd.get_ref(), &data[written..])?)) {
+            Ok(Ok(n)) => written += n,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Picks the MIME types to read from an offer advertising `offered`.
+///
+/// Only the best text type is read, since all text aliases carry the same
+/// data, followed by the formats of `sync` the source advertises.
+fn negotiate_mime_types(offered: &[String], sync: &[String]) -> Vec<String> {
+    let is_offered = |mime_type: &str| offered.iter().any(|o| o == mime_type);
+    TEXT_MIME_TYPES
+        .iter()
+        .copied()
+        .find(|mime_type| is_offered(mime_type))
+        .into_iter()
+        .chain(
+            sync.iter()
+                .map(String::as_str)
+                .filter(|mime_type| is_offered(mime_type)),
+        )
+        .map(str::to_string)
+        .collect()
+}
+
+/// Decodes text read as `mime_type` into UTF-8.
+fn decode_text(mime_type: &str, data: Vec<u8>) -> Option<String> {
+    match String::from_utf8(data) {
+        Ok(text) => Some(text),
+        // STRING is typically Latin-1
+        Err(e) if mime_type == STRING_ATOM => {
+            Some(e.into_bytes().iter().map(|&b| b as char).collect())
+        }
+        Err(_) => None,
+    }
+}
+
+/// Reads a selection pipe until EOF, giving up if the source stalls for
+/// `timeout` or sends more than `limit` bytes.
+async fn read_pipe(
+    read_file: File,
+    timeout: Duration,
+    limit: Option<usize>,
+) -> io::Result<Vec<u8>> {
+    debug!("[Wayland] Starting async read from pipe");
+    use tokio::io::AsyncReadExt;
+    let mut reader = tokio::fs::File::from_std(read_file);
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        let n = time::timeout(timeout, reader.read(&mut chunk))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "pipe read timed out"))??;
+        if n == 0 {
+            return Ok(buffer);
+        }
+        if limit.is_some_and(|limit| buffer.len() + n > limit) {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "selection exceeds the size limit",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Returns the MIME types a source holding `content` should offer.
+///
+/// Text is offered under every alias so both native Wayland and XWayland
+/// clients can find it. Our origin format comes last, so that we recognize
+/// the source when it is offered back to us.
+fn offered_mime_types(content: &ClipboardContent) -> Vec<String> {
+    let mut mime_types = Vec::new();
+    if content.has_text() {
+        mime_types.extend(TEXT_MIME_TYPES.iter().map(|m| m.to_string()));
+    }
+    mime_types.extend(
+        content
+            .mime_types()
+            .filter(|mime_type| !is_text_mime(mime_type))
+            .map(str::to_string),
+    );
+    mime_types.push(origin_mime_type().to_string());
+    mime_types
 }
 
 impl Dispatch<wl_registry::WlRegistry, GlobalData> for WaylandState {
@@ -189,23 +964,20 @@ impl Dispatch<wl_registry::WlRegistry, GlobalData> for WaylandState {
                     }
                     "wl_seat" => {
                         state.seat = Some(registry.bind::<wl_seat::WlSeat, _, _>(name, 7, qh, ()));
-                        if let Some(manager) = &state.data_control_manager
-                            && let Some(seat) = &state.seat
-                        {
-                            state.data_control_device = Some(manager.get_data_device(seat, qh, ()));
-                        }
+                        state.bind_data_control_device(qh);
+                    }
+                    "ext_data_control_manager_v1" => {
+                        let manager =
+                            registry.bind::<ExtDataControlManagerV1, _, _>(name, 1, qh, ());
+                        state.set_data_control_manager(DataControlManager::Ext(manager), qh);
                     }
                     "zwlr_data_control_manager_v1" => {
-                        state.data_control_manager =
-                            Some(registry.bind::<ZwlrDataControlManagerV1, _, _>(name, 2, qh, ()));
-                        if let Some(seat) = &state.seat {
-                            state.data_control_device = Some(
-                                state
-                                    .data_control_manager
-                                    .as_ref()
-                                    .unwrap()
-                                    .get_data_device(seat, qh, ()),
-                            );
+                        if matches!(state.data_control_manager, Some(DataControlManager::Ext(_))) {
+                            debug!("[Wayland] Using ext-data-control, ignoring zwlr_data_control");
+                        } else {
+                            let manager =
+                                registry.bind::<ZwlrDataControlManagerV1, _, _>(name, 2, qh, ());
+                            state.set_data_control_manager(DataControlManager::Wlr(manager), qh);
                         }
                     }
                     "zwp_primary_selection_device_manager_v1" => {
@@ -243,7 +1015,7 @@ impl Dispatch<wl_compositor::WlCompositor, GlobalData> for WaylandState {
 impl Dispatch<wl_seat::WlSeat, ()> for WaylandState {
     fn event(
         state: &mut Self,
-        seat: &wl_seat::WlSeat,
+        _seat: &wl_seat::WlSeat,
         event: wl_seat::Event,
         _data: &(),
         _conn: &Connection,
@@ -268,14 +1040,7 @@ impl Dispatch<wl_seat::WlSeat, ()> for WaylandState {
             state.data_control_device.is_some(),
             state.data_control_manager.is_some()
         );
-        if state.data_control_device.is_none() {
-            if let Some(manager) = &state.data_control_manager {
-                state.data_control_device = Some(manager.get_data_device(seat, qh, ()));
-                info!("[Wayland] Data control device bound");
-            } else {
-                debug!("[Wayland] Data control manager not available yet");
-            }
-        }
+        state.bind_data_control_device(qh);
     }
 }
 
@@ -293,155 +1058,10 @@ impl Dispatch<ZwlrDataControlDeviceV1, ()> for WaylandState {
                 debug!("[Wayland] New data offer: {:?}", id);
             }
             zwlr_data_control_device_v1::Event::Selection { id } => {
-                info!("[Wayland] Selection changed: {:?}", id);
-                if let Some(offer) = id {
-                    // Create pipes for receiving data
-                    match unistd::pipe() {
-                        Ok((read_fd, write_fd)) => {
-                            debug!("[Wayland] Created pipe for reading clipboard data");
-                            // Request text content with pipe
-                            offer.receive("text/plain;charset=utf-8".into(), write_fd.as_fd());
-                            // Close the write end immediately after receive() - this signals EOF to the reader
-                            // The compositor has already duplicated the fd, so it's safe to close
-                            let _ = unistd::close(write_fd);
-                            debug!("[Wayland] Closed write_fd");
-                            // Read from pipe in a separate task
-                            let read_file = File::from(read_fd);
-                            debug!("[Wayland] Created file from read_fd: {:?}", read_file);
-                            let sync_tx = state.sync_tx.clone();
-                            let content_ref = state.clipboard_content.clone();
-                            tokio::task::spawn(async move {
-                                debug!("[Wayland] Starting async read from pipe");
-                                use tokio::io::AsyncReadExt;
-                                let mut reader = tokio::fs::File::from_std(read_file);
-                                let mut buffer = Vec::new();
-                                let mut chunk = [0u8; 8192];
-                                let timeout_duration = Duration::from_secs(5);
-
-                                loop {
-                                    match time::timeout(timeout_duration, reader.read(&mut chunk))
-                                        .await
-                                    {
-                                        Ok(Ok(0)) => {
-                                            // EOF - no more data
-                                            break;
-                                        }
-                                        Ok(Ok(n)) => {
-                                            buffer.extend_from_slice(&chunk[..n]);
-                                        }
-                                        Ok(Err(e)) => {
-                                            error!("[Wayland] Failed to read from pipe: {}", e);
-                                            return;
-                                        }
-                                        Err(_) => {
-                                            warn!(
-                                                "[Wayland] Pipe read timeout after {:?}",
-                                                timeout_duration
-                                            );
-                                            break;
-                                        }
-                                    }
-                                }
-
-                                debug!("[Wayland] Read {} bytes from clipboard pipe", buffer.len());
-                                if let Ok(text) = String::from_utf8(buffer) {
-                                    if text.is_empty() {
-                                        // Wechat sends empty clipboard content to wayland,
-                                        // however it uses x11 clipboard to send the actual content.
-                                        // So we ignore empty clipboard content.
-                                        warn!("[Wayland] Received empty clipboard content");
-                                    } else {
-                                        info!(
-                                            "[Wayland] Clipboard content received: {} chars",
-                                            text.len()
-                                        );
-                                        *content_ref.lock().await = Some(text.clone());
-                                        let _ = sync_tx.send(SyncEvent::WaylandToX11 {
-                                            content: ClipboardContent::Text(text),
-                                            clipboard_type: ClipboardType::Clipboard,
-                                        });
-                                    }
-                                } else {
-                                    warn!("[Wayland] Failed to decode clipboard as UTF-8");
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            error!("[Wayland] Failed to create pipe: {}", e);
-                        }
-                    }
-                } else {
-                    // Wechat sends empty clipboard content to wayland,
-                    // clear the selection will trigger recursive call to this function.
-
-                    // // Selection cleared
-                    // info!("[Wayland] Selection cleared");
-                    // let content_ref = state.clipboard_content.clone();
-                    // let sync_tx = state.sync_tx.clone();
-                    // tokio::spawn(async move {
-                    //     *content_ref.lock().await = None;
-                    //     let _ = sync_tx.send(SyncEvent::WaylandToX11 {
-                    //         content: ClipboardContent::Empty,
-                    //         clipboard_type: ClipboardType::Clipboard,
-                    //     });
-                    // });
-                }
+                state.handle_selection(id.map(DataControlOffer::Wlr), ClipboardType::Clipboard);
             }
-            zwlr_data_control_device_v1::Event::PrimarySelection { id: _id } => {
-                // info!("[Wayland] Primary selection changed: {:?}", id);
-                // if let Some(offer) = id {
-                //     match unistd::pipe() {
-                //         Ok((read_fd, write_fd)) => {
-                //             debug!("[Wayland] Created pipe for reading primary selection data");
-                //             offer.receive("text/plain;charset=utf-8".into(), unsafe {
-                //                 BorrowedFd::borrow_raw(write_fd)
-                //             });
-                //             let read_file = unsafe { File::from_raw_fd(read_fd) };
-                //             let sync_tx = state.sync_tx.clone();
-                //             let content_ref = state.primary_content.clone();
-                //             tokio::spawn(async move {
-                //                 use tokio::io::AsyncReadExt;
-                //                 let mut reader = tokio::fs::File::from_std(read_file);
-                //                 let mut buffer = Vec::new();
-                //                 match reader.read_to_end(&mut buffer).await {
-                //                     Ok(n) => {
-                //                         debug!("[Wayland] Read {} bytes from primary pipe", n);
-                //                         if let Ok(text) = String::from_utf8(buffer) {
-                //                             info!(
-                //                                 "[Wayland] Primary selection content received: {} chars",
-                //                                 text.len()
-                //                             );
-                //                             *content_ref.lock().await = Some(text.clone());
-                //                             let _ = sync_tx.send(SyncEvent::WaylandToX11 {
-                //                                 content: ClipboardContent::Text(text),
-                //                                 clipboard_type: ClipboardType::Primary,
-                //                             });
-                //                         } else {
-                //                             warn!("[Wayland] Failed to decode primary as UTF-8");
-                //                         }
-                //                     }
-                //                     Err(e) => {
-                //                         error!("[Wayland] Failed to read from pipe: {}", e);
-                //                     }
-                //                 }
-                //             });
-                //         }
-                //         Err(e) => {
-                //             error!("[Wayland] Failed to create pipe: {}", e);
-                //         }
-                //     }
-                // } else {
-                //     info!("[Wayland] Primary selection cleared");
-                //     let content_ref = state.primary_content.clone();
-                //     let sync_tx = state.sync_tx.clone();
-                //     tokio::spawn(async move {
-                //         *content_ref.lock().await = None;
-                //         let _ = sync_tx.send(SyncEvent::WaylandToX11 {
-                //             content: ClipboardContent::Empty,
-                //             clipboard_type: ClipboardType::Primary,
-                //         });
-                //     });
-                // }
+            zwlr_data_control_device_v1::Event::PrimarySelection { id } => {
+                state.handle_selection(id.map(DataControlOffer::Wlr), ClipboardType::Primary);
             }
             zwlr_data_control_device_v1::Event::Finished => {
                 debug!("[Wayland] Data control device finished");
@@ -451,21 +1071,69 @@ impl Dispatch<ZwlrDataControlDeviceV1, ()> for WaylandState {
     }
 
     event_created_child!(WaylandState, ZwlrDataControlDeviceV1, [
-        0 => (ZwlrDataControlOfferV1, ()),
+        0 => (ZwlrDataControlOfferV1, OfferData::default()),
     ]);
 }
 
-impl Dispatch<ZwlrDataControlOfferV1, ()> for WaylandState {
+impl Dispatch<ExtDataControlDeviceV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _device: &ExtDataControlDeviceV1,
+        event: ext_data_control_device_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_data_control_device_v1::Event::DataOffer { id } => {
+                debug!("[Wayland] New data offer: {:?}", id);
+            }
+            ext_data_control_device_v1::Event::Selection { id } => {
+                state.handle_selection(id.map(DataControlOffer::Ext), ClipboardType::Clipboard);
+            }
+            ext_data_control_device_v1::Event::PrimarySelection { id } => {
+                state.handle_selection(id.map(DataControlOffer::Ext), ClipboardType::Primary);
+            }
+            ext_data_control_device_v1::Event::Finished => {
+                debug!("[Wayland] Data control device finished");
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(WaylandState, ExtDataControlDeviceV1, [
+        0 => (ExtDataControlOfferV1, OfferData::default()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlOfferV1, OfferData> for WaylandState {
     fn event(
         _state: &mut Self,
         _offer: &ZwlrDataControlOfferV1,
         event: zwlr_data_control_offer_v1::Event,
-        _data: &(),
+        data: &OfferData,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
         if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event {
             debug!("[Wayland] Offer mime type: {}", mime_type);
+            data.mime_types.lock().unwrap().push(mime_type);
+        }
+    }
+}
+
+impl Dispatch<ExtDataControlOfferV1, OfferData> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        _offer: &ExtDataControlOfferV1,
+        event: ext_data_control_offer_v1::Event,
+        data: &OfferData,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let ext_data_control_offer_v1::Event::Offer { mime_type } = event {
+            debug!("[Wayland] Offer mime type: {}", mime_type);
+            data.mime_types.lock().unwrap().push(mime_type);
         }
     }
 }
@@ -482,55 +1150,32 @@ impl Dispatch<ZwlrDataControlSourceV1, ()> for WaylandState {
         debug!("[Wayland] Source event received: {:?}", event);
         match event {
             zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
-                info!(
-                    "[Wayland] Send data for mime type: {} from source: {:?}",
-                    mime_type, source
-                );
-
-                // Determine which content to send based on source
-                let content = if Some(source) == state.clipboard_source.as_ref() {
-                    debug!("[Wayland] This is clipboard source");
-                    state.clipboard_content.blocking_lock().clone()
-                } else if Some(source) == state.primary_source.as_ref() {
-                    debug!("[Wayland] This is primary source");
-                    state.pending_primary_content.blocking_lock().clone()
-                } else {
-                    warn!(
-                        "[Wayland] Unknown source {:?}, cannot determine content. Current clipboard: {:?}, Primary: {:?}",
-                        source, state.clipboard_source, state.primary_source
-                    );
-                    // OwnedFd will be closed automatically when dropped
-                    return;
-                };
-
-                if let Some(data) = content {
-                    debug!("[Wayland] Writing {} bytes to fd", data.len());
-                    // Write the actual content to file descriptor
-                    use nix::unistd::write;
-                    match write(&fd, data.as_bytes()) {
-                        Ok(bytes_written) => {
-                            debug!("[Wayland] Successfully wrote {} bytes", bytes_written);
-                            if bytes_written != data.len() {
-                                warn!(
-                                    "[Wayland] Partial write: {} of {} bytes",
-                                    bytes_written,
-                                    data.len()
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            error!("[Wayland] Failed to write data: {}", e);
-                        }
-                    }
-                    // OwnedFd will be closed automatically when dropped
-                } else {
-                    warn!("[Wayland] No content available to send");
-                    // OwnedFd will be closed automatically when dropped
-                }
+                state.send_content(&DataControlSource::Wlr(source.clone()), mime_type, fd);
             }
             zwlr_data_control_source_v1::Event::Cancelled => {
-                debug!("[Wayland] Data source cancelled");
-                source.destroy();
+                state.source_cancelled(&DataControlSource::Wlr(source.clone()));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtDataControlSourceV1, ()> for WaylandState {
+    fn event(
+        state: &mut Self,
+        source: &ExtDataControlSourceV1,
+        event: ext_data_control_source_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        debug!("[Wayland] Source event received: {:?}", event);
+        match event {
+            ext_data_control_source_v1::Event::Send { mime_type, fd } => {
+                state.send_content(&DataControlSource::Ext(source.clone()), mime_type, fd);
+            }
+            ext_data_control_source_v1::Event::Cancelled => {
+                state.source_cancelled(&DataControlSource::Ext(source.clone()));
             }
             _ => {}
         }
@@ -582,32 +1227,11 @@ impl Dispatch<ZwpPrimarySelectionSourceV1, ()> for WaylandState {
                 );
 
                 // Get the content for primary selection
-                let content = state.pending_primary_content.blocking_lock().clone();
+                let content = state.primary_content.blocking_lock().clone();
 
-                if let Some(data) = content {
+                if let Some(data) = content.as_ref().and_then(|content| content.get(&mime_type)) {
                     debug!("[Wayland] Writing {} bytes to primary fd", data.len());
-                    // Write the actual content to file descriptor
-                    use nix::unistd::write;
-                    // Use write directly to avoid File ownership issues
-                    match write(&fd, data.as_bytes()) {
-                        Ok(bytes_written) => {
-                            debug!(
-                                "[Wayland] Successfully wrote {} bytes to primary",
-                                bytes_written
-                            );
-                            if bytes_written != data.len() {
-                                warn!(
-                                    "[Wayland] Partial write to primary: {} of {} bytes",
-                                    bytes_written,
-                                    data.len()
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            error!("[Wayland] Failed to write primary data: {}", e);
-                        }
-                    }
-                    // OwnedFd will be closed automatically when dropped
+                    spawn_write_pipe(fd, data.to_vec(), state.pipe_timeout());
                 } else {
                     warn!("[Wayland] No primary content available to send");
                     // OwnedFd will be closed automatically when dropped
@@ -626,10 +1250,95 @@ impl Dispatch<ZwlrDataControlManagerV1, ()> for WaylandState {
     fn event(
         _state: &mut Self,
         _proxy: &ZwlrDataControlManagerV1,
-        _event: wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::Event,
+        _event: zwlr_data_control_manager_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtDataControlManagerV1, ()> for WaylandState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtDataControlManagerV1,
+        _event: ext_data_control_manager_v1::Event,
         _data: &(),
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MimeTypes;
+    use crate::{IMAGE_PNG_ATOM, TEXT_HTML_ATOM, TEXT_PLAIN_ATOM, UTF8_STRING_ATOM};
+
+    fn offer(mime_types: &[&str]) -> Vec<String> {
+        mime_types.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_negotiate_picks_best_text_type_and_synced_formats() {
+        let offered = offer(&[
+            TEXT_PLAIN_ATOM,
+            "application/x-custom",
+            IMAGE_PNG_ATOM,
+            UTF8_STRING_ATOM,
+            TEXT_HTML_ATOM,
+        ]);
+
+        assert_eq!(
+            negotiate_mime_types(&offered, &MimeTypes::default().sync),
+            [UTF8_STRING_ATOM, TEXT_HTML_ATOM, IMAGE_PNG_ATOM]
+        );
+    }
+
+    #[test]
+    fn test_negotiate_skips_unsupported_offers() {
+        let sync = MimeTypes::default().sync;
+        assert!(negotiate_mime_types(&offer(&["application/x-custom"]), &sync).is_empty());
+        assert!(negotiate_mime_types(&[], &sync).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_pipe_writes_more_than_pipe_capacity() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap();
+        let data = vec![0x5a; 1024 * 1024];
+
+        let timeout = Duration::from_secs(5);
+        let reader = tokio::spawn(read_pipe(File::from(read_fd), timeout, None));
+        write_pipe(write_fd, &data, timeout).await.unwrap();
+
+        assert_eq!(reader.await.unwrap().unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_read_pipe_stops_at_the_size_limit() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap();
+        let timeout = Duration::from_secs(5);
+        // The reader closes the pipe long before everything is written
+        let writer = tokio::spawn(async move {
+            let _ = write_pipe(write_fd, &vec![0x5a; 1024 * 1024], timeout).await;
+        });
+
+        let err = read_pipe(File::from(read_fd), timeout, Some(64 * 1024))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_pipe_reports_closed_reader() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap();
+        drop(read_fd);
+
+        let err = write_pipe(write_fd, b"hello", Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
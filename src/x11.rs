@@ -2,52 +2,150 @@
 // X11 State
 // ============================================================================
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::fd::{AsFd, AsRawFd};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::{Mutex, mpsc};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{Mutex, mpsc, watch};
 use tracing::{debug, error, info, warn};
 
 use x11rb::CURRENT_TIME;
-use x11rb::connection::Connection as X11Connection;
+use x11rb::connection::{Connection as X11Connection, RequestConnection as _};
 use x11rb::protocol::Event;
 use x11rb::protocol::xfixes::{ConnectionExt as XFixesConnectionExt, SelectionEventMask};
 use x11rb::protocol::xproto::{
-    Atom, AtomEnum, ConnectionExt, CreateWindowAux, EventMask, PropertyNotifyEvent,
-    SELECTION_NOTIFY_EVENT, SelectionClearEvent, SelectionNotifyEvent, SelectionRequestEvent,
-    Window, WindowClass,
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask,
+    GetPropertyReply, Property, PropertyNotifyEvent, SELECTION_NOTIFY_EVENT, SelectionClearEvent,
+    SelectionNotifyEvent, SelectionRequestEvent, Timestamp, Window, WindowClass,
 };
+use x11rb::rust_connection::RustConnection;
 use x11rb::wrapper::ConnectionExt as _;
 
+use crate::backend::BackendCapabilities;
+use crate::config::{Config, Timeouts};
+use crate::error::ResultExt;
+use crate::reconnect::Backoff;
 use crate::{
-    CLIPBOARD_ATOM, ClipboardContent, ClipboardType, INCR_ATOM, MULTIPLE_ATOM, PRIMARY_ATOM,
-    STRING_ATOM, SyncEvent, TARGETS_ATOM, TEXT_ATOM, TEXT_PLAIN_ATOM, TEXT_PLAIN_UTF8_ATOM,
-    UTF8_STRING_ATOM,
+    CLIP_BRIDGE_PRIMARY_PROPERTY_ATOM, CLIP_BRIDGE_PROPERTY_ATOM, CLIP_BRIDGE_TIMESTAMP_ATOM,
+    CLIPBOARD_ATOM, ClipboardContent, ClipboardType, Error, INCR_ATOM, MULTIPLE_ATOM, PRIMARY_ATOM,
+    STRING_ATOM, SYNC_MIME_TYPES, TARGETS_ATOM, TEXT_ATOM, TEXT_PLAIN_ATOM, TEXT_PLAIN_UTF8_ATOM,
+    TIMESTAMP_ATOM, UTF8_STRING_ATOM, convert, is_text_mime, origin_mime_type,
 };
 
+/// An outgoing INCR transfer, advanced each time the requestor deletes the property.
+struct IncrSend {
+    property_type: Atom,
+    data: Vec<u8>,
+    offset: usize,
+    last_activity: Instant,
+}
+
+/// What an ongoing conversion is waiting for.
+enum ConversionStep {
+    /// The list of targets the owner supports.
+    Targets,
+    /// The data for a single target.
+    Target(Atom),
+    /// The next chunk of an INCR transfer for a target.
+    Incr {
+        target: Atom,
+        type_: Atom,
+        format: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// An incoming selection, fetched one target at a time as the owner answers.
+struct Conversion {
+    clipboard_type: ClipboardType,
+    /// Property on our window the owner stores the data in.
+    property: Atom,
+    /// Timestamp of the event that started the conversion.
+    time: Timestamp,
+    step: ConversionStep,
+    deadline: Instant,
+    /// Targets still to request, in order.
+    pending: VecDeque<Atom>,
+    formats: BTreeMap<String, Vec<u8>>,
+}
+
+impl Conversion {
+    /// Bytes read so far, including an unfinished INCR transfer.
+    fn size(&self) -> usize {
+        let incr = match &self.step {
+            ConversionStep::Incr { data, .. } => data.len(),
+            _ => 0,
+        };
+        self.formats.values().map(Vec::len).sum::<usize>() + incr
+    }
+}
+
 pub struct X11State {
-    conn: x11rb::rust_connection::RustConnection,
+    conn: RustConnection,
     _screen_num: usize,
     atoms: HashMap<String, Atom>,
     pub window: Window,
-    sync_tx: mpsc::UnboundedSender<SyncEvent>,
-    clipboard_content: Arc<Mutex<Option<String>>>,
-    primary_content: Arc<Mutex<Option<String>>>,
-    set_clipboard_rx: mpsc::UnboundedReceiver<(String, ClipboardType)>,
+    sync_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+    clipboard_content: Arc<Mutex<Option<ClipboardContent>>>,
+    primary_content: Arc<Mutex<Option<ClipboardContent>>>,
+    set_clipboard_rx: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    incr_sends: HashMap<(Window, Atom), IncrSend>,
+    /// Server time at which we acquired each selection we own.
+    owned_since: HashMap<Atom, Timestamp>,
+    /// Incoming conversions, keyed by selection.
+    conversions: HashMap<Atom, Conversion>,
+    timeouts: Timeouts,
+    /// Largest content we read from owners, all formats together.
+    max_size: Option<usize>,
+    /// Formats requested from owners besides text, in order of preference.
+    sync_mime_types: Vec<String>,
+    config: watch::Receiver<Config>,
+    /// Events read while waiting for a specific one, handled by the event loop.
+    pending_events: VecDeque<Event>,
 }
 
 impl X11State {
     pub fn new(
-        conn: x11rb::rust_connection::RustConnection,
+        conn: RustConnection,
+        screen_num: usize,
+        sync_tx: mpsc::UnboundedSender<(ClipboardContent, ClipboardType)>,
+        set_clipboard_rx: mpsc::UnboundedReceiver<(ClipboardContent, ClipboardType)>,
+    ) -> Result<Self, Error> {
+        let (window, atoms) = Self::init_connection(&conn, screen_num)?;
+
+        Ok(Self {
+            conn,
+            _screen_num: screen_num,
+            atoms,
+            window,
+            sync_tx,
+            clipboard_content: Arc::new(Mutex::new(None)),
+            primary_content: Arc::new(Mutex::new(None)),
+            set_clipboard_rx,
+            incr_sends: HashMap::new(),
+            owned_since: HashMap::new(),
+            conversions: HashMap::new(),
+            timeouts: Timeouts::default(),
+            max_size: None,
+            sync_mime_types: SYNC_MIME_TYPES.iter().map(|m| m.to_string()).collect(),
+            config: watch::channel(Config::default()).1,
+            pending_events: VecDeque::new(),
+        })
+    }
+
+    /// Creates the hidden window, interns our atoms and subscribes to
+    /// selection changes on a fresh connection.
+    fn init_connection(
+        conn: &RustConnection,
         screen_num: usize,
-        sync_tx: mpsc::UnboundedSender<SyncEvent>,
-        set_clipboard_rx: mpsc::UnboundedReceiver<(String, ClipboardType)>,
-    ) -> Result<Self, String> {
+    ) -> Result<(Window, HashMap<String, Atom>), Error> {
         let screen = &conn.setup().roots[screen_num];
-        let window = conn
-            .generate_id()
-            .map_err(|e| format!("Failed to generate window ID: {}", e))?;
+        let window = conn.generate_id().context("Failed to generate window ID")?;
 
         info!("[X11] Creating window: {}", window);
 
@@ -66,17 +164,16 @@ impl X11State {
             &CreateWindowAux::new()
                 .event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY),
         )
-        .map_err(|e| format!("Failed to create window: {}", e))?;
-        conn.flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
+        .context("Failed to create window")?;
+        conn.flush().context("Failed to flush connection")?;
 
         // Initialize XFixes extension
         let xfixes_query = conn
             .xfixes_query_version(5, 0)
-            .map_err(|e| format!("Failed to query XFixes version: {}", e))?;
+            .context("Failed to query XFixes version")?;
         let xfixes_reply = xfixes_query
             .reply()
-            .map_err(|e| format!("Failed to get XFixes version reply: {}", e))?;
+            .context("Failed to get XFixes version reply")?;
         info!(
             "[X11] XFixes version: {}.{}",
             xfixes_reply.major_version, xfixes_reply.minor_version
@@ -84,7 +181,7 @@ impl X11State {
 
         // Intern atoms
         let mut atoms = HashMap::new();
-        let atom_names = vec![
+        let atom_names = [
             CLIPBOARD_ATOM,
             PRIMARY_ATOM,
             TARGETS_ATOM,
@@ -95,15 +192,20 @@ impl X11State {
             STRING_ATOM,
             TEXT_PLAIN_UTF8_ATOM,
             TEXT_PLAIN_ATOM,
+            TIMESTAMP_ATOM,
+            CLIP_BRIDGE_PROPERTY_ATOM,
+            CLIP_BRIDGE_PRIMARY_PROPERTY_ATOM,
+            CLIP_BRIDGE_TIMESTAMP_ATOM,
+            origin_mime_type(),
         ];
 
-        for name in &atom_names {
+        for name in atom_names.iter().chain(SYNC_MIME_TYPES) {
             let atom = conn
                 .intern_atom(false, name.as_bytes())
-                .map_err(|e| format!("Failed to intern atom {}: {}", name, e))?;
+                .context(format!("Failed to intern atom {}", name))?;
             let reply = atom
                 .reply()
-                .map_err(|e| format!("Failed to get atom reply for {}: {}", name, e))?;
+                .context(format!("Failed to get atom reply for {}", name))?;
             atoms.insert(name.to_string(), reply.atom);
             debug!("[X11] Interned atom: {} = {}", name, reply.atom);
         }
@@ -117,7 +219,7 @@ impl X11State {
                     | SelectionEventMask::SELECTION_WINDOW_DESTROY
                     | SelectionEventMask::SELECTION_CLIENT_CLOSE,
             )
-            .map_err(|e| format!("Failed to select XFixes clipboard input: {}", e))?;
+            .context("Failed to select XFixes clipboard input")?;
             info!("[X11] XFixes selection monitoring enabled for CLIPBOARD");
         }
 
@@ -129,76 +231,174 @@ impl X11State {
                 | SelectionEventMask::SELECTION_WINDOW_DESTROY
                 | SelectionEventMask::SELECTION_CLIENT_CLOSE,
         )
-        .map_err(|e| format!("Failed to select XFixes primary input: {}", e))?;
+        .context("Failed to select XFixes primary input")?;
         info!("[X11] XFixes selection monitoring enabled for PRIMARY");
 
-        conn.flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
+        conn.flush().context("Failed to flush connection")?;
 
-        Ok(Self {
-            conn,
-            _screen_num: screen_num,
-            atoms,
-            window,
-            sync_tx,
-            clipboard_content: Arc::new(Mutex::new(None)),
-            primary_content: Arc::new(Mutex::new(None)),
-            set_clipboard_rx,
-        })
+        Ok((window, atoms))
+    }
+
+    /// Selections the X11 side of the bridge can exchange. Any format can be
+    /// offered as a target, so none is ruled out.
+    pub fn capabilities() -> BackendCapabilities {
+        BackendCapabilities {
+            selections: vec![ClipboardType::Clipboard, ClipboardType::Primary],
+            mime_types: None,
+        }
     }
 
     pub fn get_atom(&self, name: &str) -> Option<Atom> {
         self.atoms.get(name).copied()
     }
 
+    /// Returns the name of an atom we have interned.
+    fn atom_name(&self, atom: Atom) -> Option<&str> {
+        self.atoms
+            .iter()
+            .find(|(_, a)| **a == atom)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Interns `name` if it isn't known yet and returns its atom.
+    fn intern_atom(&mut self, name: &str) -> Result<Atom, Error> {
+        if let Some(atom) = self.get_atom(name) {
+            return Ok(atom);
+        }
+        let atom = self
+            .conn
+            .intern_atom(false, name.as_bytes())
+            .context(format!("Failed to intern atom {}", name))?
+            .reply()
+            .context(format!("Failed to get atom reply for {}", name))?
+            .atom;
+        self.atoms.insert(name.to_string(), atom);
+        debug!("[X11] Interned atom: {} = {}", name, atom);
+        Ok(atom)
+    }
+
+    /// Obtains the current server time.
+    ///
+    /// Appending nothing to a property on our window makes the server send a
+    /// PropertyNotify carrying its timestamp, without changing anything.
+    fn server_time(&mut self) -> Result<Timestamp, Error> {
+        let property = self.get_atom(CLIP_BRIDGE_TIMESTAMP_ATOM).unwrap();
+        self.conn
+            .change_property8(
+                x11rb::protocol::xproto::PropMode::APPEND,
+                self.window,
+                property,
+                AtomEnum::STRING,
+                &[],
+            )
+            .context("Failed to change property")?;
+        self.conn.flush().context("Failed to flush connection")?;
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            match self
+                .conn
+                .poll_for_event()
+                .context("Failed to poll for event")?
+            {
+                Some(Event::PropertyNotify(notify))
+                    if notify.window == self.window && notify.atom == property =>
+                {
+                    return Ok(notify.time);
+                }
+                // Everything else is still handled by the event loop
+                Some(event) => self.pending_events.push_back(event),
+                None => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        break;
+                    }
+                    // Block until the server sends more, or the deadline
+                    let mut fds = [PollFd::new(self.conn.stream().as_fd(), PollFlags::POLLIN)];
+                    let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+                    match poll(&mut fds, timeout) {
+                        Ok(_) | Err(Errno::EINTR) => {}
+                        Err(e) => {
+                            return Err(Error::Io(format!("Failed to poll connection: {}", e)));
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(Error::Protocol(
+            "Timed out waiting for server timestamp".to_string(),
+        ))
+    }
+
+    /// Returns `true` if we owned `selection` at `time`.
+    fn owned_at(&self, selection: Atom, time: Timestamp) -> bool {
+        let Some(&since) = self.owned_since.get(&selection) else {
+            return false;
+        };
+        // Server time wraps around, compare the signed difference
+        time == CURRENT_TIME || (time.wrapping_sub(since) as i32) >= 0
+    }
+
+    fn selection_atom(&self, clipboard_type: &ClipboardType) -> Atom {
+        match clipboard_type {
+            ClipboardType::Clipboard => self.get_atom(CLIPBOARD_ATOM).unwrap(),
+            ClipboardType::Primary => AtomEnum::PRIMARY.into(),
+        }
+    }
+
+    fn clipboard_type(&self, selection: Atom) -> ClipboardType {
+        if selection == self.get_atom(CLIPBOARD_ATOM).unwrap() {
+            ClipboardType::Clipboard
+        } else {
+            ClipboardType::Primary
+        }
+    }
+
     pub fn set_clipboard_content(
-        &self,
-        content: String,
+        &mut self,
+        content: ClipboardContent,
         clipboard_type: ClipboardType,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         info!(
             "[X11] Setting clipboard content: type={:?}, len={}",
             clipboard_type,
             content.len()
         );
 
-        let selection_atom = match clipboard_type {
-            ClipboardType::Clipboard => self.get_atom(CLIPBOARD_ATOM).unwrap(),
-            ClipboardType::Primary => AtomEnum::PRIMARY.into(),
-        };
-
-        // Store content
-        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
-        let content_bytes = content.as_bytes();
+        let selection_atom = self.selection_atom(&clipboard_type);
 
-        // Set property on our window
-        self.conn
-            .change_property8(
-                x11rb::protocol::xproto::PropMode::REPLACE,
-                self.window,
-                utf8_string,
-                utf8_string,
-                content_bytes,
-            )
-            .map_err(|e| format!("Failed to change property: {}", e))?;
-        self.conn
-            .flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
+        // Make sure every format we are about to offer has an atom
+        let mime_types = content.mime_types().map(str::to_string).collect::<Vec<_>>();
+        for mime_type in &mime_types {
+            self.intern_atom(mime_type)?;
+        }
 
-        // Claim selection ownership
+        // Claim selection ownership with a real timestamp, as ICCCM requires
+        let time = self.server_time()?;
         self.conn
-            .set_selection_owner(self.window, selection_atom, CURRENT_TIME)
-            .map_err(|e| format!("Failed to set selection owner: {}", e))?;
-        self.conn
-            .flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
+            .set_selection_owner(self.window, selection_atom, time)
+            .context("Failed to set selection owner")?;
+        let owner = self
+            .conn
+            .get_selection_owner(selection_atom)
+            .context("Failed to get selection owner")?
+            .reply()
+            .context("Failed to get selection owner reply")?;
+        if owner.owner != self.window {
+            return Err(Error::Protocol(format!(
+                "Failed to acquire selection ownership: owner is {}",
+                owner.owner
+            )));
+        }
+        self.owned_since.insert(selection_atom, time);
 
         match clipboard_type {
             ClipboardType::Clipboard => {
-                *self.clipboard_content.blocking_lock() = Some(content.clone());
+                *self.clipboard_content.blocking_lock() = Some(content);
             }
             ClipboardType::Primary => {
-                *self.primary_content.blocking_lock() = Some(content.clone());
+                *self.primary_content.blocking_lock() = Some(content);
             }
         }
 
@@ -206,25 +406,27 @@ impl X11State {
         Ok(())
     }
 
-    pub fn request_clipboard_content(&self, clipboard_type: ClipboardType) -> Result<(), String> {
+    /// Starts fetching the selection from its current owner.
+    ///
+    /// `time` is the timestamp of the event that triggered the request. The
+    /// conversion is advanced by the event loop and the content is sent to
+    /// Wayland once every target has been received.
+    pub fn request_clipboard_content(
+        &mut self,
+        clipboard_type: ClipboardType,
+        time: Timestamp,
+    ) -> Result<(), Error> {
         debug!("[X11] Requesting clipboard content: {:?}", clipboard_type);
 
-        let selection_atom = match clipboard_type {
-            ClipboardType::Clipboard => self.get_atom(CLIPBOARD_ATOM).unwrap(),
-            ClipboardType::Primary => AtomEnum::PRIMARY.into(),
-        };
-
-        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
-        let text_plain = self.get_atom(TEXT_PLAIN_ATOM).unwrap();
-        let string_atom = self.get_atom(STRING_ATOM).unwrap();
+        let selection_atom = self.selection_atom(&clipboard_type);
 
         // First check if we own the selection
         let owner = self
             .conn
             .get_selection_owner(selection_atom)
-            .map_err(|e| format!("Failed to get selection owner: {}", e))?
+            .context("Failed to get selection owner")?
             .reply()
-            .map_err(|e| format!("Failed to get selection owner reply: {}", e))?;
+            .context("Failed to get selection owner reply")?;
 
         if owner.owner == self.window {
             debug!("[X11] We own the selection, using cached content");
@@ -239,306 +441,261 @@ impl X11State {
 
         debug!("[X11] Requesting selection from owner: {}", owner.owner);
 
-        // Try multiple targets in order of preference
-        let targets = [utf8_string, text_plain, string_atom];
-        for (i, target) in targets.iter().enumerate() {
-            let property = match self.get_atom(&format!("CLIP_TEMP_{}", i)) {
-                Some(atom) => atom,
-                None => {
-                    // Create a temporary atom if needed
-                    let atom = self
-                        .conn
-                        .intern_atom(false, format!("CLIP_TEMP_{}", i).as_bytes())
-                        .unwrap();
-                    atom.reply().unwrap().atom
-                }
-            };
+        // A new owner supersedes whatever we were fetching from the old one
+        if self.conversions.remove(&selection_atom).is_some() {
+            debug!(
+                "[X11] Abandoning unfinished conversion of {:?}",
+                clipboard_type
+            );
+        }
+
+        // Ask the owner which targets it can convert to first
+        let property = self.conversion_property(&clipboard_type);
+        let targets = self.get_atom(TARGETS_ATOM).unwrap();
+        self.send_convert_selection(selection_atom, targets, property, time)?;
+        self.conversions.insert(
+            selection_atom,
+            Conversion {
+                clipboard_type,
+                property,
+                time,
+                step: ConversionStep::Targets,
+                deadline: Instant::now() + self.timeouts.conversion_reply,
+                pending: VecDeque::new(),
+                formats: BTreeMap::new(),
+            },
+        );
 
-            debug!("[X11] Trying target {} with property {}", target, property);
+        Ok(())
+    }
 
-            // Request selection
-            self.conn
-                .convert_selection(self.window, selection_atom, *target, property, CURRENT_TIME)
-                .map_err(|e| format!("Failed to convert selection: {}", e))?;
-            self.conn
-                .flush()
-                .map_err(|e| format!("Failed to flush connection: {}", e))?;
-
-            // Wait longer for response - some apps take time to respond
-            for _ in 0..10 {
-                std::thread::sleep(Duration::from_millis(20));
-
-                // Check if we got a response
-                match self.conn.poll_for_event() {
-                    Ok(Some(Event::SelectionNotify(notify))) => {
-                        if notify.property != AtomEnum::NONE.into() {
-                            debug!("[X11] Got selection notify for target {}", target);
-                            // Read the property content
-                            let prop = self
-                                .conn
-                                .get_property::<u32, u32>(
-                                    false,
-                                    self.window,
-                                    notify.property,
-                                    AtomEnum::ANY.into(),
-                                    0,
-                                    u32::MAX,
-                                )
-                                .map_err(|e| format!("Failed to get property: {}", e))?
-                                .reply()
-                                .map_err(|e| format!("Failed to get property reply: {}", e))?;
-
-                            debug!(
-                                "[X11] Property read: type={}, format={}, bytes={}",
-                                prop.type_,
-                                prop.format,
-                                prop.value.len()
-                            );
-
-                            // Check if property is empty or invalid
-                            if prop.type_ == 0 || prop.value.is_empty() {
-                                warn!("[X11] Property is empty or invalid");
-                                self.conn
-                                    .delete_property(self.window, notify.property)
-                                    .map_err(|e| format!("Failed to delete property: {}", e))?;
-                                self.conn
-                                    .flush()
-                                    .map_err(|e| format!("Failed to flush connection: {}", e))?;
-                                break;
-                            }
-
-                            // Try to decode based on property type
-                            let content = if prop.type_ == utf8_string || prop.type_ == text_plain {
-                                String::from_utf8(prop.value.clone())
-                                    .map_err(|e| format!("Failed to convert to UTF-8: {}", e))?
-                            } else if prop.type_ == string_atom {
-                                // STRING is typically Latin-1
-                                prop.value.iter().map(|&b| b as char).collect::<String>()
-                            } else {
-                                warn!(
-                                    "[X11] Unsupported property type: {} (expected UTF8_STRING={}, STRING={}, TEXT_PLAIN={})",
-                                    prop.type_, utf8_string, string_atom, text_plain
-                                );
-                                self.conn
-                                    .delete_property(self.window, notify.property)
-                                    .map_err(|e| format!("Failed to delete property: {}", e))?;
-                                self.conn
-                                    .flush()
-                                    .map_err(|e| format!("Failed to flush connection: {}", e))?;
-                                break;
-                            };
-
-                            info!(
-                                "[X11] Received clipboard content: type={:?}, len={}",
-                                clipboard_type,
-                                content.len()
-                            );
-
-                            match clipboard_type {
-                                ClipboardType::Clipboard => {
-                                    *self.clipboard_content.blocking_lock() = Some(content.clone());
-                                }
-                                ClipboardType::Primary => {
-                                    *self.primary_content.blocking_lock() = Some(content.clone());
-                                }
-                            }
-
-                            // Send sync event
-                            debug!(
-                                "[X11] Sending sync event to Wayland: type={:?}, len={}",
-                                clipboard_type,
-                                content.len()
-                            );
-                            match self.sync_tx.send(SyncEvent::X11ToWayland {
-                                content: ClipboardContent::Text(content),
-                                clipboard_type,
-                            }) {
-                                Ok(_) => debug!("[X11] Sync event sent successfully"),
-                                Err(e) => error!("[X11] Failed to send sync event: {}", e),
-                            }
-
-                            // Delete property
-                            self.conn
-                                .delete_property(self.window, notify.property)
-                                .map_err(|e| format!("Failed to delete property: {}", e))?;
-                            self.conn
-                                .flush()
-                                .map_err(|e| format!("Failed to flush connection: {}", e))?;
-
-                            // Success, don't try other targets
-                            return Ok(());
-                        } else {
-                            debug!(
-                                "[X11] Selection notify with NONE property for target {}",
-                                target
-                            );
-                            break;
-                        }
-                    }
-                    Ok(Some(Event::PropertyNotify(_))) => {
-                        // Property changed, might be our data
-                        continue;
-                    }
-                    Ok(Some(_)) => {
-                        // Other event, continue waiting
-                    }
-                    Ok(None) => {
-                        // No event yet, continue waiting
-                    }
-                    Err(e) => {
-                        debug!("[X11] Poll error: {}", e);
-                    }
-                }
-            }
+    /// Follows `config` for timeouts and synced formats, including the
+    /// versions it is updated to while the event loop runs.
+    pub fn set_config(&mut self, config: watch::Receiver<Config>) -> Result<(), Error> {
+        self.config = config;
+        self.apply_config()
+    }
 
-            debug!("[X11] No valid response for target {}", target);
+    /// Takes the latest configuration into account, interning the atoms of
+    /// newly synced formats.
+    fn apply_config(&mut self) -> Result<(), Error> {
+        let config = self.config.borrow_and_update().clone();
+        self.timeouts = config.timeouts;
+        self.max_size = config.limits.max_size;
+        for mime_type in &config.mime_types.sync {
+            self.intern_atom(mime_type)?;
         }
-
+        self.sync_mime_types = config.mime_types.sync;
+        debug!("[X11] Configuration applied");
         Ok(())
     }
 
-    pub fn handle_selection_request(&self, event: SelectionRequestEvent) -> Result<(), String> {
-        debug!("[X11] Selection request: {:?}", event);
+    fn conversion_property(&self, clipboard_type: &ClipboardType) -> Atom {
+        match clipboard_type {
+            ClipboardType::Clipboard => self.get_atom(CLIP_BRIDGE_PROPERTY_ATOM).unwrap(),
+            ClipboardType::Primary => self.get_atom(CLIP_BRIDGE_PRIMARY_PROPERTY_ATOM).unwrap(),
+        }
+    }
 
-        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
-        let targets = self.get_atom(TARGETS_ATOM).unwrap();
-        let multiple = self.get_atom(MULTIPLE_ATOM).unwrap();
+    fn send_convert_selection(
+        &self,
+        selection: Atom,
+        target: Atom,
+        property: Atom,
+        time: Timestamp,
+    ) -> Result<(), Error> {
+        self.conn
+            .convert_selection(self.window, selection, target, property, time)
+            .context("Failed to convert selection")?;
+        self.conn.flush().context("Failed to flush connection")?;
+        Ok(())
+    }
 
-        let target = event.target;
-        let mut property = event.property;
+    /// Returns the targets to request, in order of preference.
+    ///
+    /// Owners that didn't answer TARGETS are still tried with the plain text targets.
+    fn plan_targets(&self, available: Option<&[Atom]>) -> VecDeque<Atom> {
+        let is_available = |atom: &Atom| available.is_none_or(|targets| targets.contains(atom));
 
-        // Handle TARGETS request
-        if target == targets {
-            debug!("[X11] Handling TARGETS request");
-            let target_atoms = vec![
-                utf8_string,
-                self.get_atom(STRING_ATOM).unwrap(),
-                self.get_atom(TEXT_ATOM).unwrap(),
-                targets,
-            ];
-            self.conn
-                .change_property32(
-                    x11rb::protocol::xproto::PropMode::REPLACE,
-                    event.requestor,
-                    property,
-                    AtomEnum::ATOM,
-                    &target_atoms,
-                )
-                .map_err(|e| format!("Failed to change property32: {}", e))?;
+        // Text targets in order of preference, only the first that works is kept
+        let mut pending = [
+            UTF8_STRING_ATOM,
+            TEXT_PLAIN_UTF8_ATOM,
+            TEXT_PLAIN_ATOM,
+            STRING_ATOM,
+        ]
+        .map(|name| self.get_atom(name).unwrap())
+        .into_iter()
+        .filter(is_available)
+        .collect::<VecDeque<_>>();
+
+        // Every other synced format the owner advertises
+        if available.is_some() {
+            pending.extend(
+                self.sync_mime_types
+                    .iter()
+                    .filter_map(|mime_type| self.get_atom(mime_type))
+                    .filter(is_available),
+            );
         }
-        // Handle MULTIPLE request
-        else if target == multiple {
-            debug!("[X11] Handling MULTIPLE request");
-            // Read the property and handle each atom pair
-            let prop = self
-                .conn
-                .get_property(false, event.requestor, property, AtomEnum::ATOM, 0, 1024)
-                .map_err(|e| format!("Failed to get property: {}", e))?
-                .reply()
-                .map_err(|e| format!("Failed to get property reply: {}", e))?;
-
-            let atoms = prop.value32().into_iter().flatten().collect::<Vec<_>>();
-            for chunk in atoms.chunks(2) {
-                if chunk.len() == 2 {
-                    // Handle each pair (target, property)
-                    // For simplicity, we just set the property to empty
-                    self.conn
-                        .change_property8(
-                            x11rb::protocol::xproto::PropMode::REPLACE,
-                            event.requestor,
-                            chunk[1],
-                            AtomEnum::STRING,
-                            &[],
-                        )
-                        .map_err(|e| format!("Failed to change property8: {}", e))?;
-                }
+
+        pending
+    }
+
+    /// Requests the next pending target of `conversion`, or finishes it once
+    /// none is left.
+    fn advance_conversion(
+        &mut self,
+        selection: Atom,
+        mut conversion: Conversion,
+    ) -> Result<(), Error> {
+        while let Some(target) = conversion.pending.pop_front() {
+            let is_text = self.atom_name(target).is_some_and(is_text_mime);
+            if is_text && conversion.formats.contains_key(TEXT_PLAIN_UTF8_ATOM) {
+                continue;
             }
+
+            debug!("[X11] Trying target {}", target);
+            self.send_convert_selection(selection, target, conversion.property, conversion.time)?;
+            conversion.step = ConversionStep::Target(target);
+            conversion.deadline = Instant::now() + self.timeouts.conversion_reply;
+            self.conversions.insert(selection, conversion);
+            return Ok(());
         }
-        // Handle text requests
-        else if target == utf8_string
-            || target == self.get_atom(STRING_ATOM).unwrap()
-            || target == self.get_atom(TEXT_ATOM).unwrap()
-        {
-            debug!("[X11] Handling text request for target: {}", target);
-            let content = match event.selection {
-                s if s == self.get_atom(CLIPBOARD_ATOM).unwrap() => {
-                    self.clipboard_content.blocking_lock().clone()
-                }
-                s if s == AtomEnum::PRIMARY.into() => self.primary_content.blocking_lock().clone(),
-                _ => None,
-            };
 
-            if let Some(text) = content {
-                debug!("[X11] Sending text content: {} chars", text.len());
-                self.conn
-                    .change_property8(
-                        x11rb::protocol::xproto::PropMode::REPLACE,
-                        event.requestor,
-                        property,
-                        utf8_string,
-                        text.as_bytes(),
-                    )
-                    .map_err(|e| format!("Failed to change property8: {}", e))?;
-            } else {
-                warn!("[X11] No content available for request");
-                property = AtomEnum::NONE.into();
+        self.finish_conversion(conversion);
+        Ok(())
+    }
+
+    /// Handles the data the owner converted `target` to.
+    fn complete_target(
+        &mut self,
+        selection: Atom,
+        mut conversion: Conversion,
+        target: Atom,
+        prop: GetPropertyReply,
+    ) -> Result<(), Error> {
+        if target == self.get_atom(TARGETS_ATOM).unwrap() {
+            let available = prop.value32().map(|atoms| atoms.collect::<Vec<_>>());
+            debug!("[X11] Owner targets: {:?}", available);
+            let origin = self.get_atom(origin_mime_type()).unwrap();
+            if available
+                .as_ref()
+                .is_some_and(|atoms| atoms.contains(&origin))
+            {
+                debug!(
+                    "[X11] {:?} was set by us through another client, ignoring",
+                    conversion.clipboard_type
+                );
+                return Ok(());
             }
+            conversion.pending = self.plan_targets(available.as_deref());
         } else {
-            debug!("[X11] Unsupported target: {}", target);
-            property = AtomEnum::NONE.into();
+            match self.decode_property(target, &prop) {
+                Ok((mime_type, data)) => {
+                    conversion.formats.insert(mime_type, data);
+                }
+                Err(e) => debug!("[X11] No valid response for target {}: {}", target, e),
+            }
         }
 
-        // Send notification
-        self.conn
-            .send_event(
-                false,
-                event.requestor,
-                EventMask::NO_EVENT,
-                SelectionNotifyEvent {
-                    response_type: SELECTION_NOTIFY_EVENT,
-                    sequence: 0,
-                    time: event.time,
-                    requestor: event.requestor,
-                    selection: event.selection,
-                    target: event.target,
-                    property,
-                },
-            )
-            .map_err(|e| format!("Failed to send event: {}", e))?;
-        self.conn
-            .flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
-
-        Ok(())
+        self.advance_conversion(selection, conversion)
     }
 
-    pub fn handle_selection_notify(&self, event: SelectionNotifyEvent) -> Result<(), String> {
-        debug!("[X11] Selection notify: {:?}", event);
+    fn finish_conversion(&self, conversion: Conversion) {
+        let clipboard_type = conversion.clipboard_type;
+        let content = convert::complete_formats(ClipboardContent::from_formats(conversion.formats));
+        if content.is_empty() {
+            debug!("[X11] No supported content received");
+            return;
+        }
 
-        if event.property == AtomEnum::NONE.into() {
-            // Selection request failed
-            warn!("[X11] Selection request failed (property is NONE)");
-            return Ok(());
+        info!(
+            "[X11] Received clipboard content: type={:?}, formats={:?}",
+            clipboard_type, content
+        );
+
+        match clipboard_type {
+            ClipboardType::Clipboard => {
+                *self.clipboard_content.blocking_lock() = Some(content.clone());
+            }
+            ClipboardType::Primary => {
+                *self.primary_content.blocking_lock() = Some(content.clone());
+            }
         }
 
-        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
-        let string_atom = self.get_atom(STRING_ATOM).unwrap();
-        let text_plain = self.get_atom(TEXT_PLAIN_ATOM).unwrap();
+        // Send sync event
+        debug!(
+            "[X11] Sending sync event to Wayland: type={:?}, len={}",
+            clipboard_type,
+            content.len()
+        );
+        match self.sync_tx.send((content, clipboard_type)) {
+            Ok(_) => debug!("[X11] Sync event sent successfully"),
+            Err(e) => error!("[X11] Failed to send sync event: {}", e),
+        }
+    }
+
+    /// Gives up on conversion steps whose owner didn't answer in time.
+    fn expire_conversions(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let expired = self
+            .conversions
+            .iter()
+            .filter(|(_, conversion)| conversion.deadline <= now)
+            .map(|(selection, _)| *selection)
+            .collect::<Vec<_>>();
+
+        for selection in expired {
+            let Some(mut conversion) = self.conversions.remove(&selection) else {
+                continue;
+            };
+            match &conversion.step {
+                ConversionStep::Targets => {
+                    debug!("[X11] TARGETS request timed out, trying text targets");
+                    conversion.pending = self.plan_targets(None);
+                }
+                ConversionStep::Target(target) => {
+                    debug!(
+                        "[X11] Conversion to {} timed out",
+                        self.target_name(*target)
+                    );
+                }
+                ConversionStep::Incr { target, data, .. } => {
+                    warn!(
+                        "[X11] INCR conversion to {} timed out after {} bytes",
+                        self.target_name(*target),
+                        data.len()
+                    );
+                }
+            }
+            self.advance_conversion(selection, conversion)?;
+        }
+
+        Ok(())
+    }
 
-        // Read the property - try different types
+    /// Names `target` in logs, by number if we didn't intern it.
+    fn target_name(&self, target: Atom) -> String {
+        self.atom_name(target)
+            .map_or_else(|| target.to_string(), str::to_string)
+    }
+
+    /// Reads and deletes a property the selection owner stored data in.
+    fn read_selection_property(&self, property: Atom) -> Result<GetPropertyReply, Error> {
         let prop = self
             .conn
             .get_property::<u32, u32>(
                 false,
                 self.window,
-                event.property,
+                property,
                 AtomEnum::ANY.into(),
                 0,
                 u32::MAX,
             )
-            .map_err(|e| format!("Failed to get property: {}", e))?
+            .context("Failed to get property")?
             .reply()
-            .map_err(|e| format!("Failed to get property reply: {}", e))?;
+            .context("Failed to get property reply")?;
 
         debug!(
             "[X11] Property read: type={}, format={}, bytes={}",
@@ -547,89 +704,428 @@ impl X11State {
             prop.value.len()
         );
 
-        // Check if property is empty or invalid
-        if prop.type_ == 0 || prop.value.is_empty() {
-            warn!("[X11] Property is empty or invalid");
-            // Delete the property and return
-            self.conn
-                .delete_property(self.window, event.property)
-                .map_err(|e| format!("Failed to delete property: {}", e))?;
-            self.conn
-                .flush()
-                .map_err(|e| format!("Failed to flush connection: {}", e))?;
-            return Ok(());
-        }
+        // Delete property. For INCR this also tells the owner to start sending chunks.
+        self.conn
+            .delete_property(self.window, property)
+            .context("Failed to delete property")?;
+        self.conn.flush().context("Failed to flush connection")?;
 
-        // Try to decode based on property type
-        let content = if prop.type_ == utf8_string || prop.type_ == text_plain {
-            String::from_utf8(prop.value.clone())
-                .map_err(|e| format!("Failed to convert to UTF-8: {}", e))?
-        } else if prop.type_ == string_atom {
-            // STRING is typically Latin-1
-            prop.value.iter().map(|&b| b as char).collect::<String>()
-        } else {
-            warn!(
-                "[X11] Unsupported property type: {} (expected UTF8_STRING={}, STRING={}, TEXT_PLAIN={})",
-                prop.type_, utf8_string, string_atom, text_plain
-            );
-            // Delete the property and return
-            self.conn
-                .delete_property(self.window, event.property)
-                .map_err(|e| format!("Failed to delete property: {}", e))?;
-            self.conn
-                .flush()
-                .map_err(|e| format!("Failed to flush connection: {}", e))?;
-            return Ok(());
-        };
+        Ok(prop)
+    }
 
-        let clipboard_type = if event.selection == self.get_atom(CLIPBOARD_ATOM).unwrap() {
-            ClipboardType::Clipboard
-        } else {
-            ClipboardType::Primary
+    /// Reads the next chunk of the INCR transfer of `selection`.
+    ///
+    /// The owner writes one chunk at a time and waits for us to delete it;
+    /// a zero-length chunk marks the end of the data.
+    fn receive_incr_chunk(&mut self, selection: Atom) -> Result<(), Error> {
+        let Some(mut conversion) = self.conversions.remove(&selection) else {
+            return Ok(());
         };
 
+        // Read and delete the chunk, which asks the owner for the next one
+        let chunk = self
+            .conn
+            .get_property::<u32, u32>(
+                true,
+                self.window,
+                conversion.property,
+                AtomEnum::ANY.into(),
+                0,
+                u32::MAX,
+            )
+            .context("Failed to get property")?
+            .reply()
+            .context("Failed to get property reply")?;
+        self.conn.flush().context("Failed to flush connection")?;
+
+        if self.exceeds_max_size(&conversion, chunk.value.len()) {
+            return Ok(());
+        }
+        let ConversionStep::Incr {
+            target,
+            type_,
+            format,
+            data,
+        } = &mut conversion.step
+        else {
+            self.conversions.insert(selection, conversion);
+            return Ok(());
+        };
+        let target = *target;
+
+        if chunk.value.is_empty() {
+            debug!("[X11] INCR transfer complete: {} bytes", data.len());
+            // The type and format of the data are those of the chunks
+            let mut prop = chunk;
+            if !data.is_empty() {
+                prop.type_ = *type_;
+                prop.format = *format;
+            }
+            prop.value = std::mem::take(data);
+            prop.value_len = prop.value.len() as u32 / (u32::from(prop.format.max(8)) / 8);
+            return self.complete_target(selection, conversion, target, prop);
+        }
+
+        debug!("[X11] INCR chunk received: {} bytes", chunk.value.len());
+        if data.is_empty() {
+            *type_ = chunk.type_;
+            *format = chunk.format;
+        }
+        data.extend_from_slice(&chunk.value);
+        conversion.deadline = Instant::now() + self.timeouts.incr_chunk;
+        self.conversions.insert(selection, conversion);
+
+        Ok(())
+    }
+
+    /// Returns `true`, giving up on `conversion`, if `incoming` more bytes
+    /// would take it over the size limit.
+    fn exceeds_max_size(&self, conversion: &Conversion, incoming: usize) -> bool {
+        let Some(max_size) = self.max_size else {
+            return false;
+        };
+        if conversion.size() + incoming <= max_size {
+            return false;
+        }
         info!(
-            "[X11] Received clipboard content: type={:?}, len={}",
-            clipboard_type,
-            content.len()
+            "[X11] {:?} content exceeds {} bytes, skipping",
+            conversion.clipboard_type, max_size
         );
+        true
+    }
 
-        match clipboard_type {
-            ClipboardType::Clipboard => {
-                *self.clipboard_content.blocking_lock() = Some(content.clone());
-            }
-            ClipboardType::Primary => {
-                *self.primary_content.blocking_lock() = Some(content.clone());
-            }
+    /// Decodes a converted property into a `(mime_type, bytes)` pair.
+    ///
+    /// Text targets are normalized to UTF-8 under [`TEXT_PLAIN_UTF8_ATOM`].
+    fn decode_property(
+        &self,
+        target: Atom,
+        prop: &GetPropertyReply,
+    ) -> Result<(String, Vec<u8>), Error> {
+        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
+        let string_atom = self.get_atom(STRING_ATOM).unwrap();
+        let text_plain = self.get_atom(TEXT_PLAIN_ATOM).unwrap();
+        let text_plain_utf8 = self.get_atom(TEXT_PLAIN_UTF8_ATOM).unwrap();
+
+        // Check if property is empty or invalid
+        if prop.type_ == 0 || prop.value.is_empty() {
+            return Err(Error::Protocol("Property is empty or invalid".to_string()));
         }
 
-        // Send sync event
-        let _ = self.sync_tx.send(SyncEvent::X11ToWayland {
-            content: ClipboardContent::Text(content),
-            clipboard_type,
-        });
+        // Non-text formats are passed through untouched
+        if let Some(mime_type) = self.atom_name(target)
+            && self.sync_mime_types.iter().any(|m| m == mime_type)
+        {
+            return Ok((mime_type.to_string(), prop.value.clone()));
+        }
 
-        // Delete the property
-        self.conn
-            .delete_property(self.window, event.property)
-            .map_err(|e| format!("Failed to delete property: {}", e))?;
+        // Try to decode based on property type
+        let text = if prop.type_ == utf8_string
+            || prop.type_ == text_plain
+            || prop.type_ == text_plain_utf8
+        {
+            String::from_utf8(prop.value.clone())
+                .map_err(|e| Error::Encoding(format!("Failed to convert to UTF-8: {}", e)))?
+        } else if prop.type_ == string_atom {
+            // STRING is typically Latin-1
+            prop.value.iter().map(|&b| b as char).collect::<String>()
+        } else {
+            return Err(Error::Encoding(format!(
+                "Unsupported property type: {} (expected UTF8_STRING={}, STRING={}, TEXT_PLAIN={})",
+                prop.type_, utf8_string, string_atom, text_plain
+            )));
+        };
+
+        Ok((TEXT_PLAIN_UTF8_ATOM.to_string(), text.into_bytes()))
+    }
+
+    pub fn handle_selection_request(&mut self, event: SelectionRequestEvent) -> Result<(), Error> {
+        debug!("[X11] Selection request: {:?}", event);
+
+        let multiple = self.get_atom(MULTIPLE_ATOM).unwrap();
+
+        let content = match event.selection {
+            s if s == self.get_atom(CLIPBOARD_ATOM).unwrap() => {
+                self.clipboard_content.blocking_lock().clone()
+            }
+            s if s == AtomEnum::PRIMARY.into() => self.primary_content.blocking_lock().clone(),
+            _ => None,
+        };
+
+        // Refuse requests for selections we don't own, or that predate our ownership
+        let converted = if !self.owned_at(event.selection, event.time) {
+            debug!(
+                "[X11] Refusing selection request: not the owner at time {}",
+                event.time
+            );
+            false
+        }
+        // Handle MULTIPLE request
+        else if event.target == multiple {
+            self.convert_multiple(
+                event.requestor,
+                event.selection,
+                event.property,
+                content.as_ref(),
+            )?
+        } else {
+            self.convert_target(
+                event.requestor,
+                event.selection,
+                event.target,
+                event.property,
+                content.as_ref(),
+            )?
+        };
+        let property = if converted {
+            event.property
+        } else {
+            AtomEnum::NONE.into()
+        };
+
+        // Send notification
         self.conn
-            .flush()
-            .map_err(|e| format!("Failed to flush connection: {}", e))?;
+            .send_event(
+                false,
+                event.requestor,
+                EventMask::NO_EVENT,
+                SelectionNotifyEvent {
+                    response_type: SELECTION_NOTIFY_EVENT,
+                    sequence: 0,
+                    time: event.time,
+                    requestor: event.requestor,
+                    selection: event.selection,
+                    target: event.target,
+                    property,
+                },
+            )
+            .context("Failed to send event")?;
+        self.conn.flush().context("Failed to flush connection")?;
 
         Ok(())
     }
 
-    pub fn handle_selection_clear(&self, event: SelectionClearEvent) -> Result<(), String> {
-        debug!("[X11] Selection clear: {:?}", event);
+    /// Converts `content` to `target` into `property` on `requestor`.
+    ///
+    /// Returns `false` if the target is unsupported or no data is available.
+    fn convert_target(
+        &mut self,
+        requestor: Window,
+        selection: Atom,
+        target: Atom,
+        property: Atom,
+        content: Option<&ClipboardContent>,
+    ) -> Result<bool, Error> {
+        let utf8_string = self.get_atom(UTF8_STRING_ATOM).unwrap();
+        let targets = self.get_atom(TARGETS_ATOM).unwrap();
 
-        let clipboard_type = if event.selection == self.get_atom(CLIPBOARD_ATOM).unwrap() {
-            ClipboardType::Clipboard
+        // Handle TIMESTAMP request
+        if target == self.get_atom(TIMESTAMP_ATOM).unwrap() {
+            let Some(&time) = self.owned_since.get(&selection) else {
+                return Ok(false);
+            };
+            debug!("[X11] Handling TIMESTAMP request: {}", time);
+            self.conn
+                .change_property32(
+                    x11rb::protocol::xproto::PropMode::REPLACE,
+                    requestor,
+                    property,
+                    AtomEnum::INTEGER,
+                    &[time],
+                )
+                .context("Failed to change property32")?;
+            return Ok(true);
+        }
+
+        // Handle TARGETS request
+        if target == targets {
+            debug!("[X11] Handling TARGETS request");
+            let mut target_atoms = vec![
+                targets,
+                self.get_atom(MULTIPLE_ATOM).unwrap(),
+                self.get_atom(TIMESTAMP_ATOM).unwrap(),
+                self.get_atom(origin_mime_type()).unwrap(),
+            ];
+            if let Some(content) = content {
+                if content.has_text() {
+                    target_atoms.extend(
+                        [
+                            UTF8_STRING_ATOM,
+                            STRING_ATOM,
+                            TEXT_ATOM,
+                            TEXT_PLAIN_UTF8_ATOM,
+                            TEXT_PLAIN_ATOM,
+                        ]
+                        .map(|name| self.get_atom(name).unwrap()),
+                    );
+                }
+                target_atoms.extend(
+                    content
+                        .mime_types()
+                        .filter(|mime_type| !is_text_mime(mime_type))
+                        .filter_map(|mime_type| self.get_atom(mime_type)),
+                );
+            }
+            self.conn
+                .change_property32(
+                    x11rb::protocol::xproto::PropMode::REPLACE,
+                    requestor,
+                    property,
+                    AtomEnum::ATOM,
+                    &target_atoms,
+                )
+                .context("Failed to change property32")?;
+            return Ok(true);
+        }
+
+        // Handle content requests
+        let Some(target_name) = self.atom_name(target) else {
+            debug!("[X11] Unsupported target: {}", target);
+            return Ok(false);
+        };
+        debug!(
+            "[X11] Handling content request for target: {} ({})",
+            target, target_name
+        );
+        // Text is always sent as UTF8_STRING, other formats keep their target type
+        let property_type = if is_text_mime(target_name) {
+            utf8_string
         } else {
-            ClipboardType::Primary
+            target
+        };
+
+        let Some(data) = content.and_then(|c| c.get(target_name)) else {
+            warn!("[X11] No content available for request");
+            return Ok(false);
+        };
+        debug!("[X11] Sending content: {} bytes", data.len());
+        self.write_property_data(requestor, property, property_type, data)?;
+        Ok(true)
+    }
+
+    /// Handles a MULTIPLE request by converting each `(target, property)` pair
+    /// listed in `property`.
+    ///
+    /// Pairs that can't be converted get their property replaced with `None`,
+    /// as ICCCM requires.
+    fn convert_multiple(
+        &mut self,
+        requestor: Window,
+        selection: Atom,
+        property: Atom,
+        content: Option<&ClipboardContent>,
+    ) -> Result<bool, Error> {
+        debug!("[X11] Handling MULTIPLE request");
+
+        if property == AtomEnum::NONE.into() {
+            warn!("[X11] MULTIPLE request without a property");
+            return Ok(false);
+        }
+
+        // Read the ATOM_PAIR list of conversions
+        let prop = self
+            .conn
+            .get_property(false, requestor, property, AtomEnum::ANY, 0, u32::MAX)
+            .context("Failed to get property")?
+            .reply()
+            .context("Failed to get property reply")?;
+        let Some(atoms) = prop.value32() else {
+            warn!("[X11] MULTIPLE property is not a list of atom pairs");
+            return Ok(false);
+        };
+        let mut pairs = atoms.collect::<Vec<_>>();
+
+        let multiple = self.get_atom(MULTIPLE_ATOM).unwrap();
+        for pair in pairs.chunks_exact_mut(2) {
+            let (target, pair_property) = (pair[0], pair[1]);
+            // Nested MULTIPLE requests are refused
+            let converted = target != multiple
+                && pair_property != AtomEnum::NONE.into()
+                && self.convert_target(requestor, selection, target, pair_property, content)?;
+            if !converted {
+                debug!("[X11] MULTIPLE conversion to target {} failed", target);
+                pair[1] = AtomEnum::NONE.into();
+            }
+        }
+
+        // Report failed conversions back to the requestor
+        self.conn
+            .change_property32(
+                x11rb::protocol::xproto::PropMode::REPLACE,
+                requestor,
+                property,
+                prop.type_,
+                &pairs,
+            )
+            .context("Failed to change property32")?;
+
+        Ok(true)
+    }
+
+    pub fn handle_selection_notify(&mut self, event: SelectionNotifyEvent) -> Result<(), Error> {
+        debug!("[X11] Selection notify: {:?}", event);
+
+        // Only answers to the step a conversion is waiting for are of interest
+        let expected = match self.conversions.get(&event.selection).map(|c| &c.step) {
+            Some(ConversionStep::Targets) => self.get_atom(TARGETS_ATOM),
+            Some(ConversionStep::Target(target)) => Some(*target),
+            Some(ConversionStep::Incr { .. }) | None => None,
+        };
+        if event.requestor != self.window || Some(event.target) != expected {
+            debug!("[X11] Ignoring unexpected selection notify");
+            return Ok(());
+        }
+        let Some(mut conversion) = self.conversions.remove(&event.selection) else {
+            return Ok(());
         };
 
+        if event.property == AtomEnum::NONE.into() {
+            // Selection request failed
+            debug!(
+                "[X11] Selection notify with NONE property for target {}",
+                event.target
+            );
+            if matches!(conversion.step, ConversionStep::Targets) {
+                conversion.pending = self.plan_targets(None);
+            }
+            return self.advance_conversion(event.selection, conversion);
+        }
+
+        let prop = self.read_selection_property(event.property)?;
+
+        // Large data is sent in chunks, as PropertyNotify events tell us
+        if prop.type_ == self.get_atom(INCR_ATOM).unwrap() {
+            let size_hint = prop.value32().and_then(|mut v| v.next());
+            info!(
+                "[X11] Receiving INCR transfer: target={}, size hint={:?} bytes",
+                event.target, size_hint
+            );
+            // The hint is a lower bound of the size
+            let size_hint = size_hint.map_or(0, |size| size as usize);
+            if self.exceeds_max_size(&conversion, size_hint) {
+                return Ok(());
+            }
+            conversion.step = ConversionStep::Incr {
+                target: event.target,
+                type_: AtomEnum::NONE.into(),
+                format: 8,
+                data: Vec::new(),
+            };
+            conversion.deadline = Instant::now() + self.timeouts.incr_chunk;
+            self.conversions.insert(event.selection, conversion);
+            return Ok(());
+        }
+
+        self.complete_target(event.selection, conversion, event.target, prop)
+    }
+
+    pub fn handle_selection_clear(&mut self, event: SelectionClearEvent) -> Result<(), Error> {
+        debug!("[X11] Selection clear: {:?}", event);
+
+        let clipboard_type = self.clipboard_type(event.selection);
+
         info!("[X11] Lost ownership of selection: {:?}", clipboard_type);
+        self.owned_since.remove(&event.selection);
 
         match clipboard_type {
             ClipboardType::Clipboard => {
@@ -643,62 +1139,420 @@ impl X11State {
         Ok(())
     }
 
-    pub fn handle_property_notify(&self, event: PropertyNotifyEvent) -> Result<(), String> {
+    pub fn handle_property_notify(&mut self, event: PropertyNotifyEvent) -> Result<(), Error> {
         debug!(
             "[X11] Property notify: atom={}, state={:?}",
             event.atom, event.state
         );
+
+        // A new value on our window is the next chunk of an incoming INCR transfer
+        if event.window == self.window && event.state == Property::NEW_VALUE {
+            let selection = self
+                .conversions
+                .iter()
+                .find(|(_, c)| {
+                    c.property == event.atom && matches!(c.step, ConversionStep::Incr { .. })
+                })
+                .map(|(selection, _)| *selection);
+            if let Some(selection) = selection {
+                return self.receive_incr_chunk(selection);
+            }
+            return Ok(());
+        }
+
+        // A requestor deleting the property asks for the next INCR chunk
+        if event.state != Property::DELETE {
+            return Ok(());
+        }
+        let chunk_size = self.max_chunk_size();
+        let Some(transfer) = self.incr_sends.get_mut(&(event.window, event.atom)) else {
+            return Ok(());
+        };
+
+        let end = (transfer.offset + chunk_size).min(transfer.data.len());
+        let chunk = &transfer.data[transfer.offset..end];
+        debug!(
+            "[X11] Sending INCR chunk: {}..{} of {} bytes",
+            transfer.offset,
+            end,
+            transfer.data.len()
+        );
+        self.conn
+            .change_property8(
+                x11rb::protocol::xproto::PropMode::REPLACE,
+                event.window,
+                event.atom,
+                transfer.property_type,
+                chunk,
+            )
+            .context("Failed to change property8")?;
+
+        // The zero-length chunk written after the last data chunk ends the transfer
+        let finished = chunk.is_empty();
+        transfer.offset = end;
+        transfer.last_activity = Instant::now();
+
+        if finished {
+            info!(
+                "[X11] INCR transfer to window {} complete: {} bytes",
+                event.window,
+                transfer.data.len()
+            );
+            self.incr_sends.remove(&(event.window, event.atom));
+            self.release_requestor(event.window)?;
+        }
+
+        self.conn.flush().context("Failed to flush connection")?;
+
+        Ok(())
+    }
+
+    /// Forgets INCR transfers whose requestor stopped reading.
+    fn expire_incr_sends(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let expired = self
+            .incr_sends
+            .iter()
+            .filter(|(_, transfer)| transfer.last_activity + self.timeouts.incr_send <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for (window, property) in expired {
+            let Some(transfer) = self.incr_sends.remove(&(window, property)) else {
+                continue;
+            };
+            warn!(
+                "[X11] Abandoning INCR transfer to window {} property {} after {} of {} bytes",
+                window,
+                property,
+                transfer.offset,
+                transfer.data.len()
+            );
+            self.release_requestor(window)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops listening to property changes on `window` once no INCR transfer
+    /// to it is left.
+    fn release_requestor(&self, window: Window) -> Result<(), Error> {
+        if self.incr_sends.keys().any(|(w, _)| *w == window) {
+            return Ok(());
+        }
+        self.conn
+            .change_window_attributes(
+                window,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT),
+            )
+            .context("Failed to change window attributes")?;
+        Ok(())
+    }
+
+    /// Largest property we write in one request, the same limit GTK uses.
+    fn max_chunk_size(&self) -> usize {
+        (self.conn.maximum_request_bytes() - 100).min(262144)
+    }
+
+    /// Stores `data` in `property` on `requestor`, switching to INCR when it
+    /// doesn't fit in a single request.
+    fn write_property_data(
+        &mut self,
+        requestor: Window,
+        property: Atom,
+        property_type: Atom,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if data.len() <= self.max_chunk_size() {
+            self.conn
+                .change_property8(
+                    x11rb::protocol::xproto::PropMode::REPLACE,
+                    requestor,
+                    property,
+                    property_type,
+                    data,
+                )
+                .context("Failed to change property8")?;
+            return Ok(());
+        }
+
+        info!(
+            "[X11] Starting INCR transfer to window {}: {} bytes",
+            requestor,
+            data.len()
+        );
+
+        // Watch the requestor for deletions of the property
+        self.conn
+            .change_window_attributes(
+                requestor,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+            )
+            .context("Failed to change window attributes")?;
+
+        // Announce INCR with a lower bound of the size
+        let incr = self.get_atom(INCR_ATOM).unwrap();
+        self.conn
+            .change_property32(
+                x11rb::protocol::xproto::PropMode::REPLACE,
+                requestor,
+                property,
+                incr,
+                &[u32::try_from(data.len()).unwrap_or(u32::MAX)],
+            )
+            .context("Failed to change property32")?;
+
+        self.incr_sends.insert(
+            (requestor, property),
+            IncrSend {
+                property_type,
+                data: data.to_vec(),
+                offset: 0,
+                last_activity: Instant::now(),
+            },
+        );
+
         Ok(())
     }
 
-    pub fn run_event_loop(&mut self) -> Result<(), String> {
+    /// Runs the X11 event loop until the command channel is closed.
+    ///
+    /// Must be called from a blocking thread inside a tokio runtime (e.g.
+    /// `spawn_blocking`). The loop sleeps until either the X connection becomes
+    /// readable, a command arrives or a conversion times out, so it is fully
+    /// idle otherwise.
+    pub fn run_event_loop(&mut self) -> Result<(), Error> {
         info!("[X11] Starting event loop");
 
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| {
+            Error::Runtime(format!("X11 event loop requires a tokio runtime: {}", e))
+        })?;
+        let _guard = runtime.enter();
+        let x11_fd = AsyncFd::with_interest(self.conn.stream().as_raw_fd(), Interest::READABLE)
+            .map_err(|e| Error::ConnectionLost(format!("Failed to watch X11 connection: {}", e)))?;
+
         loop {
-            // Check for set clipboard requests
-            if let Ok((content, clipboard_type)) = self.set_clipboard_rx.try_recv() {
-                let _ = self.set_clipboard_content(content, clipboard_type);
+            // Pick up a reloaded configuration
+            if self.config.has_changed().unwrap_or(false) {
+                match self.apply_config() {
+                    Err(e) if e.is_connection_lost() => return Err(e),
+                    Err(e) => error!("[X11] Failed to apply configuration: {}", e),
+                    Ok(()) => {}
+                }
+            }
+
+            // Process every X11 event, including those x11rb queued while
+            // waiting for replies, since they won't make the socket readable again
+            while let Some(event) = self.next_event()? {
+                match self.handle_event(event) {
+                    Err(e) if e.is_connection_lost() => return Err(e),
+                    Err(e) => error!("[X11] Failed to handle event: {}", e),
+                    Ok(()) => {}
+                }
+            }
+
+            // Give up on selection owners that stopped answering, and on
+            // requestors that stopped reading
+            match self.expire_conversions() {
+                Err(e) if e.is_connection_lost() => return Err(e),
+                Err(e) => error!("[X11] Failed to expire conversions: {}", e),
+                Ok(()) => {}
+            }
+            match self.expire_incr_sends() {
+                Err(e) if e.is_connection_lost() => return Err(e),
+                Err(e) => error!("[X11] Failed to expire INCR transfers: {}", e),
+                Ok(()) => {}
             }
 
-            // Process X11 events
-            match self.conn.poll_for_event() {
-                Ok(Some(event)) => match event {
-                    Event::SelectionRequest(e) => self.handle_selection_request(e)?,
-                    Event::SelectionNotify(e) => self.handle_selection_notify(e)?,
-                    Event::SelectionClear(e) => self.handle_selection_clear(e)?,
-                    Event::PropertyNotify(e) => self.handle_property_notify(e)?,
-                    Event::XfixesSelectionNotify(e) => self.handle_xfixes_selection_notify(e)?,
-                    _ => {
-                        debug!("[X11] Unhandled event: {:?}", event);
+            // Flush any pending requests
+            self.conn.flush().context("Failed to flush connection")?;
+
+            // Wait for the X server, a set clipboard request or the next
+            // conversion or INCR transfer deadline
+            let deadline = self
+                .conversions
+                .values()
+                .map(|c| c.deadline)
+                .chain(
+                    self.incr_sends
+                        .values()
+                        .map(|t| t.last_activity + self.timeouts.incr_send),
+                )
+                .min()
+                .map(tokio::time::Instant::from_std);
+            let command = runtime.block_on(async {
+                tokio::select! {
+                    guard = x11_fd.readable() => {
+                        // Everything readable is consumed at the top of the loop
+                        if let Ok(mut guard) = guard {
+                            guard.clear_ready();
+                        }
+                        None
                     }
-                },
-                Ok(None) => {
-                    // No events, continue
+                    command = self.set_clipboard_rx.recv() => Some(command),
+                    _ = async {
+                        match deadline {
+                            Some(deadline) => tokio::time::sleep_until(deadline).await,
+                            None => std::future::pending().await,
+                        }
+                    } => None,
                 }
-                Err(e) => {
-                    debug!("[X11] Poll error: {}", e);
+            });
+
+            match command {
+                Some(Some((content, clipboard_type))) => {
+                    match self.set_clipboard_content(content, clipboard_type) {
+                        Err(e) if e.is_connection_lost() => return Err(e),
+                        Err(e) => error!("[X11] Failed to set clipboard content: {}", e),
+                        Ok(()) => {}
+                    }
+                }
+                Some(None) => {
+                    info!("[X11] Set clipboard channel closed, stopping event loop");
+                    return Ok(());
                 }
+                None => {}
             }
+        }
+    }
 
-            // Flush any pending requests
-            let _ = self.conn.flush();
+    /// Runs the event loop, reconnecting through `connect` with backoff
+    /// whenever the X server goes away, e.g. when XWayland is restarted.
+    ///
+    /// Same requirements as [`run_event_loop`](Self::run_event_loop). Returns
+    /// once the command channel is closed, or on errors other than a lost
+    /// connection.
+    pub fn run_with_reconnect(
+        &mut self,
+        mut connect: impl FnMut() -> Result<(RustConnection, usize), Error>,
+    ) -> Result<(), Error> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|e| {
+            Error::Runtime(format!("X11 event loop requires a tokio runtime: {}", e))
+        })?;
+        let mut backoff = Backoff::default();
 
-            // Sleep to avoid busy waiting
-            std::thread::sleep(Duration::from_millis(10));
+        loop {
+            match self.run_event_loop() {
+                Err(e) if e.is_connection_lost() => warn!("[X11] {}, reconnecting", e),
+                result => return result,
+            }
+
+            let mut owned = self.owned_selections();
+            loop {
+                // Keep taking commands while disconnected, so the latest
+                // content is the one claimed once reconnected
+                let deadline = tokio::time::Instant::now() + backoff.next_delay();
+                while let Some(command) = runtime.block_on(async {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => None,
+                        command = self.set_clipboard_rx.recv() => Some(command),
+                    }
+                }) {
+                    let Some((content, clipboard_type)) = command else {
+                        info!("[X11] Set clipboard channel closed while reconnecting");
+                        return Ok(());
+                    };
+                    *self.selection_content(&clipboard_type).blocking_lock() = Some(content);
+                    if !owned.contains(&clipboard_type) {
+                        owned.push(clipboard_type);
+                    }
+                }
+
+                match connect().and_then(|(conn, screen_num)| self.reconnect(conn, screen_num)) {
+                    Ok(()) => break,
+                    Err(e) => warn!("[X11] Failed to reconnect: {}", e),
+                }
+            }
+            backoff.reset();
+
+            for clipboard_type in owned {
+                let content = self
+                    .selection_content(&clipboard_type)
+                    .blocking_lock()
+                    .clone();
+                let Some(content) = content else {
+                    continue;
+                };
+                info!(
+                    "[X11] Claiming {:?} again after reconnecting",
+                    clipboard_type
+                );
+                if let Err(e) = self.set_clipboard_content(content, clipboard_type) {
+                    error!("[X11] Failed to claim selection again: {}", e);
+                }
+            }
         }
     }
 
-    fn handle_xfixes_selection_notify(
+    /// Switches to `conn`, recreating the window and the XFixes subscriptions.
+    ///
+    /// Conversions and transfers in progress on the old connection are dropped.
+    fn reconnect(&mut self, conn: RustConnection, screen_num: usize) -> Result<(), Error> {
+        let (window, atoms) = Self::init_connection(&conn, screen_num)?;
+        info!("[X11] Reconnected, window: {}", window);
+
+        self.conn = conn;
+        self._screen_num = screen_num;
+        self.window = window;
+        self.atoms = atoms;
+        self.incr_sends.clear();
+        self.owned_since.clear();
+        self.conversions.clear();
+        self.pending_events.clear();
+
+        // Atoms of the formats added by the configuration are gone too
+        self.apply_config()
+    }
+
+    /// Selections we currently own.
+    fn owned_selections(&self) -> Vec<ClipboardType> {
+        self.owned_since
+            .keys()
+            .map(|selection| self.clipboard_type(*selection))
+            .collect()
+    }
+
+    fn selection_content(
         &self,
+        clipboard_type: &ClipboardType,
+    ) -> &Arc<Mutex<Option<ClipboardContent>>> {
+        match clipboard_type {
+            ClipboardType::Clipboard => &self.clipboard_content,
+            ClipboardType::Primary => &self.primary_content,
+        }
+    }
+
+    /// Returns the next event, starting with those set aside while waiting for a reply.
+    fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Some(event));
+        }
+        self.conn
+            .poll_for_event()
+            .context("Failed to poll for event")
+    }
+
+    fn handle_event(&mut self, event: Event) -> Result<(), Error> {
+        match event {
+            Event::SelectionRequest(e) => self.handle_selection_request(e),
+            Event::SelectionNotify(e) => self.handle_selection_notify(e),
+            Event::SelectionClear(e) => self.handle_selection_clear(e),
+            Event::PropertyNotify(e) => self.handle_property_notify(e),
+            Event::XfixesSelectionNotify(e) => self.handle_xfixes_selection_notify(e),
+            _ => {
+                debug!("[X11] Unhandled event: {:?}", event);
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_xfixes_selection_notify(
+        &mut self,
         event: x11rb::protocol::xfixes::SelectionNotifyEvent,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         debug!("[X11] XFixes selection notify: {:?}", event);
 
-        let clipboard_type = if event.selection == self.get_atom(CLIPBOARD_ATOM).unwrap() {
-            ClipboardType::Clipboard
-        } else {
-            ClipboardType::Primary
-        };
+        let clipboard_type = self.clipboard_type(event.selection);
 
         // Check if we own the selection
         if event.owner == self.window {
@@ -712,57 +1566,9 @@ impl X11State {
                 "[X11] Selection changed via XFixes: type={:?}, owner={}",
                 clipboard_type, event.owner
             );
-            let _ = self.request_clipboard_content(clipboard_type);
+            self.request_clipboard_content(clipboard_type, event.timestamp)?;
         }
 
         Ok(())
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use tokio::sync::mpsc::unbounded_channel;
-
-    use super::*;
-
-    #[test]
-    fn test_x11_state_initialization() {
-        let (conn, screen_num) = x11rb::connect(None).unwrap();
-        let (sync_tx, _sync_rx) = unbounded_channel();
-        let (_set_clipboard_tx, set_clipboard_rx) = unbounded_channel();
-
-        let x11_state = X11State::new(conn, screen_num, sync_tx, set_clipboard_rx);
-        assert!(x11_state.is_ok(), "Failed to initialize X11State");
-    }
-
-    #[test]
-    fn test_atom_interning() {
-        let (conn, screen_num) = x11rb::connect(None).unwrap();
-        let (sync_tx, _sync_rx) = unbounded_channel();
-        let (_set_clipboard_tx, set_clipboard_rx) = unbounded_channel();
-
-        let x11_state = X11State::new(conn, screen_num, sync_tx, set_clipboard_rx).unwrap();
-
-        // Test that all required atoms are interned
-        let required_atoms = vec![
-            CLIPBOARD_ATOM,
-            PRIMARY_ATOM,
-            TARGETS_ATOM,
-            MULTIPLE_ATOM,
-            INCR_ATOM,
-            UTF8_STRING_ATOM,
-            TEXT_ATOM,
-            STRING_ATOM,
-            TEXT_PLAIN_UTF8_ATOM,
-            TEXT_PLAIN_ATOM,
-        ];
-
-        for atom_name in required_atoms {
-            assert!(
-                x11_state.get_atom(atom_name).is_some(),
-                "Atom {} not interned",
-                atom_name
-            );
-        }
-    }
-}
@@ -0,0 +1,423 @@
+//! End-to-end tests running the real X11 and Wayland backends against
+//! headless display servers.
+
+mod common;
+
+use clip_bridge::backend::{
+    BackendCapabilities, ChannelBackend, ClipboardBackend, MemoryBackend, MemoryClipboard,
+};
+use clip_bridge::sync::SyncEngine;
+use clip_bridge::wayland::{GlobalData, WaylandState};
+use clip_bridge::x11::X11State;
+use clip_bridge::{ClipboardContent, ClipboardType, origin_mime_type};
+use tokio::runtime::Runtime;
+use x11rb::CURRENT_TIME;
+use x11rb::protocol::xproto::AtomEnum;
+
+use common::wayland::{DataControl, WaylandServer};
+use common::x11::{INCR_CHUNK, X11Client, Xvfb};
+use common::{SYNC_TIMEOUT, wait_for};
+
+/// A running bridge, stopped when dropped.
+struct Bridge {
+    runtime: Runtime,
+    engine: SyncEngine,
+}
+
+impl Bridge {
+    fn start(first: impl ClipboardBackend, second: impl ClipboardBackend) -> Self {
+        let runtime = Runtime::new().unwrap();
+        let mut engine = SyncEngine::new(first, second);
+        runtime.block_on(async { engine.start() });
+        Self { runtime, engine }
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        // Stopping the engine closes the backend channels, which ends their event loops
+        self.runtime.block_on(self.engine.stop());
+    }
+}
+
+/// Runs the real Wayland backend against `server` on `runtime`.
+fn wayland_backend(server: &WaylandServer, runtime: &Runtime) -> ChannelBackend {
+    let (backend, endpoint) = ChannelBackend::new("Wayland", WaylandState::capabilities());
+    let connector = server.connector();
+    runtime.spawn_blocking(move || {
+        let conn = connector.connect();
+        let mut event_queue = conn.new_event_queue();
+        let qh = event_queue.handle();
+        let mut state = WaylandState::new(qh.clone(), endpoint.sync_tx);
+        conn.display().get_registry(&qh, GlobalData);
+        event_queue.roundtrip(&mut state).unwrap();
+        state
+            .run_with_reconnect(conn, event_queue, endpoint.set_clipboard_rx, || {
+                Ok(connector.connect())
+            })
+            .unwrap();
+    });
+    backend
+}
+
+/// Runs the real X11 backend against `xvfb` on `runtime`.
+fn x11_backend(xvfb: &Xvfb, runtime: &Runtime) -> ChannelBackend {
+    let (backend, endpoint) = ChannelBackend::new("X11", X11State::capabilities());
+    let (conn, screen_num) = xvfb.connect();
+    let display = xvfb.display().to_string();
+    runtime.spawn_blocking(move || {
+        let mut state = X11State::new(
+            conn,
+            screen_num,
+            endpoint.sync_tx,
+            endpoint.set_clipboard_rx,
+        )
+        .unwrap();
+        state
+            .run_with_reconnect(|| Ok(x11rb::connect(Some(&display))?))
+            .unwrap();
+    });
+    backend
+}
+
+fn memory_backend() -> (MemoryBackend, MemoryClipboard) {
+    MemoryBackend::new(
+        "Memory",
+        BackendCapabilities {
+            selections: vec![ClipboardType::Clipboard, ClipboardType::Primary],
+            mime_types: None,
+        },
+    )
+}
+
+/// Waits for the next write made to a memory backend.
+fn next_write(
+    bridge: &Bridge,
+    clipboard: &mut MemoryClipboard,
+) -> Option<(ClipboardContent, ClipboardType)> {
+    bridge
+        .runtime
+        .block_on(async { tokio::time::timeout(SYNC_TIMEOUT, clipboard.next_write()).await })
+        .expect("timed out waiting for a write")
+}
+
+#[test]
+fn test_wayland_copies_are_synced_both_ways() {
+    for protocol in DataControl::ALL {
+        wayland_copies_are_synced_both_ways(protocol);
+    }
+}
+
+/// Copies both ways through a compositor only offering `protocol`.
+fn wayland_copies_are_synced_both_ways(protocol: DataControl) {
+    let server = WaylandServer::start_with(&[protocol]);
+    let runtime = Runtime::new().unwrap();
+    let wayland = wayland_backend(&server, &runtime);
+    let (memory, mut clipboard) = memory_backend();
+    let bridge = Bridge::start(wayland, memory);
+
+    for clipboard_type in [ClipboardType::Clipboard, ClipboardType::Primary] {
+        // Wayland -> bridge
+        let text = format!("from wayland {:?} over {:?}", clipboard_type, protocol);
+        server.copy(ClipboardContent::text(&text), clipboard_type.clone());
+        assert_eq!(
+            next_write(&bridge, &mut clipboard),
+            Some((ClipboardContent::text(&text), clipboard_type.clone()))
+        );
+
+        // Bridge -> Wayland
+        let text = format!("to wayland {:?} over {:?}", clipboard_type, protocol);
+        clipboard.copy(ClipboardContent::text(&text), clipboard_type.clone());
+        wait_for("the bridge to own the Wayland selection", || {
+            server
+                .paste_text(clipboard_type.clone())
+                .filter(|pasted| *pasted == text)
+        });
+    }
+
+    // Nothing but the Wayland copies above may have reached the bridge
+    drop(bridge);
+    assert_eq!(clipboard.try_next_write(), None);
+    drop(runtime);
+}
+
+#[test]
+fn test_identical_copies_take_the_wayland_selection_over() {
+    let server = WaylandServer::start();
+    let runtime = Runtime::new().unwrap();
+    let wayland = wayland_backend(&server, &runtime);
+    let (memory, mut clipboard) = memory_backend();
+    let bridge = Bridge::start(wayland, memory);
+    let bridge_owns_clipboard = || {
+        server
+            .mime_types(ClipboardType::Clipboard)
+            .iter()
+            .any(|mime_type| mime_type == origin_mime_type())
+            .then_some(())
+    };
+
+    let content = ClipboardContent::text("same");
+    clipboard.copy(content.clone(), ClipboardType::Clipboard);
+    wait_for(
+        "the bridge to own the Wayland selection",
+        bridge_owns_clipboard,
+    );
+
+    // A Wayland client copying the same text is still a change
+    server.copy(content.clone(), ClipboardType::Clipboard);
+    assert_eq!(
+        next_write(&bridge, &mut clipboard),
+        Some((content.clone(), ClipboardType::Clipboard))
+    );
+
+    // And so is copying it again on the other side
+    clipboard.copy(content, ClipboardType::Clipboard);
+    wait_for(
+        "the bridge to own the Wayland selection again",
+        bridge_owns_clipboard,
+    );
+
+    drop(bridge);
+    assert_eq!(clipboard.try_next_write(), None);
+    drop(runtime);
+}
+
+#[test]
+fn test_wayland_side_survives_compositor_restart() {
+    let server = WaylandServer::start();
+    let runtime = Runtime::new().unwrap();
+    let wayland = wayland_backend(&server, &runtime);
+    let (memory, mut clipboard) = memory_backend();
+    let bridge = Bridge::start(wayland, memory);
+
+    let text = "owned before restart";
+    clipboard.copy(ClipboardContent::text(text), ClipboardType::Clipboard);
+    wait_for("the bridge to own the Wayland selection", || {
+        server
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == text)
+    });
+
+    server.restart();
+
+    // The selection we owned is set again on the new compositor
+    wait_for("the bridge to set the selection again", || {
+        server
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == text)
+    });
+
+    // And Wayland copies are synced again
+    let text = "copied after restart";
+    server.copy(ClipboardContent::text(text), ClipboardType::Clipboard);
+    assert_eq!(
+        next_write(&bridge, &mut clipboard),
+        Some((ClipboardContent::text(text), ClipboardType::Clipboard))
+    );
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_x11_and_wayland_copies_are_synced_both_ways() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let server = WaylandServer::start();
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let wayland = wayland_backend(&server, &runtime);
+    let bridge = Bridge::start(x11, wayland);
+    let client = X11Client::start(&xvfb);
+
+    for clipboard_type in [ClipboardType::Clipboard, ClipboardType::Primary] {
+        // X11 -> Wayland
+        let text = format!("from x11 {:?}", clipboard_type);
+        client.copy(&text, clipboard_type.clone());
+        wait_for("the X11 copy to reach Wayland", || {
+            server
+                .paste_text(clipboard_type.clone())
+                .filter(|pasted| *pasted == text)
+        });
+
+        // Wayland -> X11
+        let text = format!("from wayland {:?}", clipboard_type);
+        server.copy(ClipboardContent::text(&text), clipboard_type.clone());
+        wait_for("the Wayland copy to reach X11", || {
+            client
+                .paste_text(clipboard_type.clone())
+                .filter(|pasted| *pasted == text)
+        });
+    }
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_x11_side_survives_x_server_restart() {
+    let Some(mut xvfb) = Xvfb::start() else {
+        return;
+    };
+    let server = WaylandServer::start();
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let wayland = wayland_backend(&server, &runtime);
+    let bridge = Bridge::start(x11, wayland);
+
+    let text = "owned before restart";
+    server.copy(ClipboardContent::text(text), ClipboardType::Clipboard);
+    {
+        let client = X11Client::start(&xvfb);
+        wait_for("the Wayland copy to reach X11", || {
+            client
+                .paste_text(ClipboardType::Clipboard)
+                .filter(|pasted| pasted == text)
+        });
+    }
+
+    xvfb.restart();
+    let client = X11Client::start(&xvfb);
+
+    // The selection we owned is claimed again on the new server
+    wait_for("the bridge to claim the selection again", || {
+        client
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == text)
+    });
+
+    // And X11 copies are synced again
+    let text = "copied after restart";
+    client.copy(text, ClipboardType::Clipboard);
+    wait_for("the X11 copy to reach Wayland", || {
+        server
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == text)
+    });
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_large_selections_are_sent_to_x11_incrementally() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let (memory, memory_clipboard) = memory_backend();
+    let bridge = Bridge::start(x11, memory);
+    let client = X11Client::start(&xvfb);
+
+    // Well over the 256 KiB the bridge writes at once
+    let text = "0123456789abcdef".repeat(64 * 1024);
+    memory_clipboard.copy(ClipboardContent::text(&text), ClipboardType::Clipboard);
+    wait_for("the large copy to reach X11", || {
+        client
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| *pasted == text)
+    });
+    // The transfer is over, so the next one starts afresh
+    assert_eq!(client.paste_text(ClipboardType::Clipboard), Some(text));
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_large_x11_selections_are_received_incrementally() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let server = WaylandServer::start();
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let wayland = wayland_backend(&server, &runtime);
+    let bridge = Bridge::start(x11, wayland);
+    let client = X11Client::start(&xvfb);
+
+    // Sixteen times what the client writes at once
+    let text = "0123456789abcdef".repeat(INCR_CHUNK);
+    client.copy(&text, ClipboardType::Clipboard);
+    wait_for("the large X11 copy to reach Wayland", || {
+        server
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| *pasted == text)
+    });
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_unsupported_pairs_of_multiple_requests_are_refused() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let (memory, memory_clipboard) = memory_backend();
+    let bridge = Bridge::start(x11, memory);
+    let client = X11Client::start(&xvfb);
+
+    memory_clipboard.copy(ClipboardContent::text("batched"), ClipboardType::Clipboard);
+    wait_for("the copy to reach X11", || {
+        client
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == "batched")
+    });
+
+    // There is no image to convert to, so that pair is rewritten to None
+    let converted = client
+        .convert_multiple(ClipboardType::Clipboard, &["UTF8_STRING", "image/png"])
+        .expect("MULTIPLE request refused");
+    assert_eq!(converted, vec![Some(b"batched".to_vec()), None]);
+
+    drop(bridge);
+    drop(runtime);
+}
+
+#[test]
+fn test_x11_requests_predating_the_ownership_are_refused() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let runtime = Runtime::new().unwrap();
+    let x11 = x11_backend(&xvfb, &runtime);
+    let (memory, memory_clipboard) = memory_backend();
+    let bridge = Bridge::start(x11, memory);
+    let client = X11Client::start(&xvfb);
+
+    memory_clipboard.copy(ClipboardContent::text("owned"), ClipboardType::Clipboard);
+    wait_for("the copy to reach X11", || {
+        client
+            .paste_text(ClipboardType::Clipboard)
+            .filter(|pasted| pasted == "owned")
+    });
+
+    let timestamp = client
+        .convert(ClipboardType::Clipboard, "TIMESTAMP", CURRENT_TIME)
+        .expect("TIMESTAMP request refused");
+    assert_eq!(timestamp.type_, u32::from(AtomEnum::INTEGER));
+    let owned_since = timestamp.value32().and_then(|mut values| values.next());
+    let owned_since = owned_since.expect("TIMESTAMP reply is not an INTEGER");
+
+    assert!(
+        client
+            .convert(
+                ClipboardType::Clipboard,
+                "UTF8_STRING",
+                owned_since.wrapping_sub(1)
+            )
+            .is_none()
+    );
+    let converted = client
+        .convert(ClipboardType::Clipboard, "UTF8_STRING", owned_since)
+        .expect("request at the ownership time refused");
+    assert_eq!(converted.value, b"owned");
+
+    drop(bridge);
+    drop(runtime);
+}
@@ -0,0 +1,24 @@
+//! Headless display servers and clients used by the integration tests.
+
+#![allow(dead_code)]
+
+pub mod wayland;
+pub mod x11;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a copy may take to reach the other side of the bridge.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Polls `check` until it returns `Some`, or panics after [`SYNC_TIMEOUT`].
+pub fn wait_for<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + SYNC_TIMEOUT;
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,562 @@
+//! A minimal in-process Wayland compositor offering `ext_data_control_manager_v1`
+//! and `zwlr_data_control_manager_v1`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+
+use clip_bridge::{ClipboardContent, ClipboardType};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use nix::unistd;
+use wayland_client::Connection;
+use wayland_protocols::ext::data_control::v1::server::{
+    ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+    ext_data_control_manager_v1::{self, ExtDataControlManagerV1},
+    ext_data_control_offer_v1::{self, ExtDataControlOfferV1},
+    ext_data_control_source_v1::{self, ExtDataControlSourceV1},
+};
+use wayland_protocols_wlr::data_control::v1::server::{
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_manager_v1::{self, ZwlrDataControlManagerV1},
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+use wayland_server::protocol::wl_seat::{self, WlSeat};
+use wayland_server::{
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource,
+};
+
+// ============================================================================
+// Test Server
+// ============================================================================
+
+/// Data control protocols a [`WaylandServer`] can offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataControl {
+    Ext,
+    Wlr,
+}
+
+impl DataControl {
+    pub const ALL: [Self; 2] = [Self::Ext, Self::Wlr];
+}
+
+/// A Wayland compositor running on its own thread, whose selections can be
+/// set and read as a regular client would.
+pub struct WaylandServer {
+    commands: mpsc::Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Connects new clients to a [`WaylandServer`], from any thread.
+#[derive(Clone)]
+pub struct Connector {
+    commands: mpsc::Sender<Command>,
+}
+
+impl Connector {
+    pub fn connect(&self) -> Connection {
+        let (client, server) = UnixStream::pair().expect("failed to create socket pair");
+        self.commands.send(Command::InsertClient(server)).unwrap();
+        Connection::from_socket(client).expect("failed to connect to test server")
+    }
+}
+
+enum Command {
+    InsertClient(UnixStream),
+    Restart,
+    Copy(ClipboardType, BTreeMap<String, Vec<u8>>),
+    Paste(ClipboardType, String, mpsc::Sender<Option<File>>),
+    MimeTypes(ClipboardType, mpsc::Sender<Vec<String>>),
+    Stop,
+}
+
+impl WaylandServer {
+    /// Starts a compositor offering both data control protocols, like most
+    /// real ones do.
+    pub fn start() -> Self {
+        Self::start_with(&DataControl::ALL)
+    }
+
+    /// Starts a compositor offering only the `protocols` given.
+    pub fn start_with(protocols: &[DataControl]) -> Self {
+        let protocols = protocols.to_vec();
+        let (commands, command_rx) = mpsc::channel();
+        let thread = thread::spawn(move || run_server(command_rx, &protocols));
+        Self {
+            commands,
+            thread: Some(thread),
+        }
+    }
+
+    /// Connects a new client to the compositor.
+    pub fn connect(&self) -> Connection {
+        self.connector().connect()
+    }
+
+    pub fn connector(&self) -> Connector {
+        Connector {
+            commands: self.commands.clone(),
+        }
+    }
+
+    /// Disconnects every client and forgets the selections, like a
+    /// compositor restart.
+    pub fn restart(&self) {
+        self.commands.send(Command::Restart).unwrap();
+    }
+
+    /// Sets `clipboard_type` to `content`, as a regular Wayland client would.
+    pub fn copy(&self, content: ClipboardContent, clipboard_type: ClipboardType) {
+        let formats = match content {
+            ClipboardContent::Mime(formats) => formats,
+            ClipboardContent::Empty => BTreeMap::new(),
+        };
+        self.commands
+            .send(Command::Copy(clipboard_type, formats))
+            .unwrap();
+    }
+
+    /// Reads `clipboard_type` as `mime_type`, or `None` if nothing offers it.
+    pub fn paste(&self, clipboard_type: ClipboardType, mime_type: &str) -> Option<Vec<u8>> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.commands
+            .send(Command::Paste(
+                clipboard_type,
+                mime_type.to_string(),
+                reply_tx,
+            ))
+            .unwrap();
+        let mut pipe = reply_rx.recv().unwrap()?;
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+
+    /// MIME types `clipboard_type` is currently offered as.
+    pub fn mime_types(&self, clipboard_type: ClipboardType) -> Vec<String> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.commands
+            .send(Command::MimeTypes(clipboard_type, reply_tx))
+            .unwrap();
+        reply_rx.recv().unwrap()
+    }
+
+    /// Reads the text of `clipboard_type`, as a native Wayland client would.
+    pub fn paste_text(&self, clipboard_type: ClipboardType) -> Option<String> {
+        self.paste(clipboard_type, clip_bridge::TEXT_PLAIN_UTF8_ATOM)
+            .and_then(|data| String::from_utf8(data).ok())
+    }
+}
+
+impl Drop for WaylandServer {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_server(commands: mpsc::Receiver<Command>, protocols: &[DataControl]) {
+    let mut display = new_display(protocols);
+    let mut dh = display.handle();
+    let mut state = ServerState::default();
+
+    loop {
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                Command::Restart => {
+                    // Dropping the display closes every client connection
+                    display = new_display(protocols);
+                    dh = display.handle();
+                    state = ServerState::default();
+                }
+                Command::InsertClient(stream) => {
+                    display
+                        .handle()
+                        .insert_client(stream, Arc::new(()))
+                        .expect("failed to insert client");
+                }
+                Command::Copy(clipboard_type, formats) => {
+                    let data = (!formats.is_empty()).then(|| Selection::Test(Arc::new(formats)));
+                    state.set_selection(&dh, clipboard_type, data);
+                }
+                Command::Paste(clipboard_type, mime_type, reply) => {
+                    let pipe = state
+                        .selection(&clipboard_type)
+                        .filter(|selection| selection.mime_types().contains(&mime_type))
+                        .and_then(|selection| {
+                            let (read_fd, write_fd) = unistd::pipe().ok()?;
+                            selection.send(mime_type, write_fd);
+                            Some(File::from(read_fd))
+                        });
+                    let _ = reply.send(pipe);
+                }
+                Command::MimeTypes(clipboard_type, reply) => {
+                    let mime_types = state
+                        .selection(&clipboard_type)
+                        .map(|selection| selection.mime_types())
+                        .unwrap_or_default();
+                    let _ = reply.send(mime_types);
+                }
+                Command::Stop => return,
+            }
+        }
+
+        display
+            .dispatch_clients(&mut state)
+            .expect("failed to dispatch clients");
+        display.flush_clients().expect("failed to flush clients");
+
+        // Wake up regularly to pick up commands
+        let fd = display.backend().poll_fd();
+        let _ = poll(
+            &mut [PollFd::new(fd, PollFlags::POLLIN)],
+            PollTimeout::from(10u8),
+        );
+    }
+}
+
+fn new_display(protocols: &[DataControl]) -> Display<ServerState> {
+    let display = Display::<ServerState>::new().expect("failed to create display");
+    let dh = display.handle();
+    dh.create_global::<ServerState, WlSeat, ()>(7, ());
+    for protocol in protocols {
+        match protocol {
+            DataControl::Ext => dh.create_global::<ServerState, ExtDataControlManagerV1, ()>(1, ()),
+            DataControl::Wlr => {
+                dh.create_global::<ServerState, ZwlrDataControlManagerV1, ()>(2, ())
+            }
+        };
+    }
+    display
+}
+
+// ============================================================================
+// Data Control
+// ============================================================================
+
+/// What a selection is currently set to.
+#[derive(Debug, Clone)]
+enum Selection {
+    /// A source created by a data control client.
+    Source(Source),
+    /// Content set by the test itself.
+    Test(Arc<BTreeMap<String, Vec<u8>>>),
+}
+
+impl Selection {
+    fn mime_types(&self) -> Vec<String> {
+        match self {
+            Self::Source(source) => source
+                .data()
+                .map(|data| data.mime_types.lock().unwrap().clone())
+                .unwrap_or_default(),
+            Self::Test(formats) => formats.keys().cloned().collect(),
+        }
+    }
+
+    /// Writes the selection as `mime_type` to `fd`, closing it once done.
+    fn send(&self, mime_type: String, fd: OwnedFd) {
+        match self {
+            Self::Source(source) => source.send(mime_type, fd.as_fd()),
+            Self::Test(formats) => {
+                let data = formats.get(&mime_type).cloned().unwrap_or_default();
+                thread::spawn(move || {
+                    let _ = File::from(fd).write_all(&data);
+                });
+            }
+        }
+    }
+}
+
+/// MIME types offered by a data control source.
+#[derive(Debug, Default)]
+struct SourceData {
+    mime_types: Mutex<Vec<String>>,
+}
+
+/// A data control source of either protocol.
+#[derive(Debug, Clone)]
+enum Source {
+    Ext(ExtDataControlSourceV1),
+    Wlr(ZwlrDataControlSourceV1),
+}
+
+impl Source {
+    fn data(&self) -> Option<&SourceData> {
+        match self {
+            Self::Ext(source) => source.data(),
+            Self::Wlr(source) => source.data(),
+        }
+    }
+
+    fn send(&self, mime_type: String, fd: BorrowedFd) {
+        match self {
+            Self::Ext(source) => source.send(mime_type, fd),
+            Self::Wlr(source) => source.send(mime_type, fd),
+        }
+    }
+
+    fn cancelled(&self) {
+        match self {
+            Self::Ext(source) => source.cancelled(),
+            Self::Wlr(source) => source.cancelled(),
+        }
+    }
+}
+
+/// A data control device of either protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Device {
+    Ext(ExtDataControlDeviceV1),
+    Wlr(ZwlrDataControlDeviceV1),
+}
+
+impl Device {
+    /// Sends `selection` to the device as a new `clipboard_type` offer.
+    fn announce(
+        &self,
+        dh: &DisplayHandle,
+        clipboard_type: &ClipboardType,
+        selection: Option<Selection>,
+    ) {
+        match self {
+            Self::Ext(device) => announce_ext(dh, device, clipboard_type, selection),
+            Self::Wlr(device) => announce_wlr(dh, device, clipboard_type, selection),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ServerState {
+    devices: Vec<Device>,
+    clipboard: Option<Selection>,
+    primary: Option<Selection>,
+}
+
+impl ServerState {
+    fn selection(&self, clipboard_type: &ClipboardType) -> Option<Selection> {
+        match clipboard_type {
+            ClipboardType::Clipboard => self.clipboard.clone(),
+            ClipboardType::Primary => self.primary.clone(),
+        }
+    }
+
+    /// Replaces a selection, cancelling the previous source and announcing
+    /// the new one to every device.
+    fn set_selection(
+        &mut self,
+        dh: &DisplayHandle,
+        clipboard_type: ClipboardType,
+        selection: Option<Selection>,
+    ) {
+        let current = match clipboard_type {
+            ClipboardType::Clipboard => &mut self.clipboard,
+            ClipboardType::Primary => &mut self.primary,
+        };
+        if let Some(Selection::Source(old)) = std::mem::replace(current, selection) {
+            old.cancelled();
+        }
+        for device in &self.devices {
+            self.announce(dh, device, &clipboard_type);
+        }
+    }
+
+    /// Sends a new offer for the current `clipboard_type` selection to `device`.
+    fn announce(&self, dh: &DisplayHandle, device: &Device, clipboard_type: &ClipboardType) {
+        device.announce(dh, clipboard_type, self.selection(clipboard_type));
+    }
+}
+
+impl GlobalDispatch<WlSeat, ()> for ServerState {
+    fn bind(
+        _state: &mut Self,
+        _dh: &DisplayHandle,
+        _client: &Client,
+        resource: New<WlSeat>,
+        _data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<WlSeat, ()> for ServerState {
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _seat: &WlSeat,
+        _request: wl_seat::Request,
+        _data: &(),
+        _dh: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+/// Implements the server side of a data control protocol. The ext and wlr
+/// protocols only differ by the names of their interfaces.
+macro_rules! data_control {
+    (
+        $variant:ident,
+        $announce:ident,
+        $manager:ident::$Manager:ident,
+        $device:ident::$Device:ident,
+        $source:ident::$Source:ident,
+        $offer:ident::$Offer:ident $(,)?
+    ) => {
+        /// Sends `selection` to `device` as a new `clipboard_type` offer.
+        fn $announce(
+            dh: &DisplayHandle,
+            device: &$Device,
+            clipboard_type: &ClipboardType,
+            selection: Option<Selection>,
+        ) {
+            let Some(client) = device.client() else {
+                return;
+            };
+            let offer = selection.and_then(|selection| {
+                let mime_types = selection.mime_types();
+                let offer = client
+                    .create_resource::<$Offer, _, ServerState>(dh, device.version(), selection)
+                    .ok()?;
+                device.data_offer(&offer);
+                for mime_type in mime_types {
+                    offer.offer(mime_type);
+                }
+                Some(offer)
+            });
+            match clipboard_type {
+                ClipboardType::Clipboard => device.selection(offer.as_ref()),
+                ClipboardType::Primary => device.primary_selection(offer.as_ref()),
+            }
+        }
+
+        impl GlobalDispatch<$Manager, ()> for ServerState {
+            fn bind(
+                _state: &mut Self,
+                _dh: &DisplayHandle,
+                _client: &Client,
+                resource: New<$Manager>,
+                _data: &(),
+                data_init: &mut DataInit<'_, Self>,
+            ) {
+                data_init.init(resource, ());
+            }
+        }
+
+        impl Dispatch<$Manager, ()> for ServerState {
+            fn request(
+                state: &mut Self,
+                _client: &Client,
+                _manager: &$Manager,
+                request: $manager::Request,
+                _data: &(),
+                dh: &DisplayHandle,
+                data_init: &mut DataInit<'_, Self>,
+            ) {
+                match request {
+                    $manager::Request::CreateDataSource { id } => {
+                        data_init.init(id, SourceData::default());
+                    }
+                    $manager::Request::GetDataDevice { id, .. } => {
+                        let device = Device::$variant(data_init.init(id, ()));
+                        // New devices are told about the current selections right away
+                        state.announce(dh, &device, &ClipboardType::Clipboard);
+                        state.announce(dh, &device, &ClipboardType::Primary);
+                        state.devices.push(device);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        impl Dispatch<$Device, ()> for ServerState {
+            fn request(
+                state: &mut Self,
+                _client: &Client,
+                _device: &$Device,
+                request: $device::Request,
+                _data: &(),
+                dh: &DisplayHandle,
+                _data_init: &mut DataInit<'_, Self>,
+            ) {
+                let source = |source: $Source| Selection::Source(Source::$variant(source));
+                match request {
+                    $device::Request::SetSelection { source: new } => {
+                        state.set_selection(dh, ClipboardType::Clipboard, new.map(source));
+                    }
+                    $device::Request::SetPrimarySelection { source: new } => {
+                        state.set_selection(dh, ClipboardType::Primary, new.map(source));
+                    }
+                    _ => {}
+                }
+            }
+
+            fn destroyed(
+                state: &mut Self,
+                _client: wayland_server::backend::ClientId,
+                device: &$Device,
+                _data: &(),
+            ) {
+                let device = Device::$variant(device.clone());
+                state.devices.retain(|d| *d != device);
+            }
+        }
+
+        impl Dispatch<$Source, SourceData> for ServerState {
+            fn request(
+                _state: &mut Self,
+                _client: &Client,
+                _source: &$Source,
+                request: $source::Request,
+                data: &SourceData,
+                _dh: &DisplayHandle,
+                _data_init: &mut DataInit<'_, Self>,
+            ) {
+                if let $source::Request::Offer { mime_type } = request {
+                    data.mime_types.lock().unwrap().push(mime_type);
+                }
+            }
+        }
+
+        impl Dispatch<$Offer, Selection> for ServerState {
+            fn request(
+                _state: &mut Self,
+                _client: &Client,
+                _offer: &$Offer,
+                request: $offer::Request,
+                selection: &Selection,
+                _dh: &DisplayHandle,
+                _data_init: &mut DataInit<'_, Self>,
+            ) {
+                if let $offer::Request::Receive { mime_type, fd } = request {
+                    selection.send(mime_type, fd);
+                }
+            }
+        }
+    };
+}
+
+data_control!(
+    Ext,
+    announce_ext,
+    ext_data_control_manager_v1::ExtDataControlManagerV1,
+    ext_data_control_device_v1::ExtDataControlDeviceV1,
+    ext_data_control_source_v1::ExtDataControlSourceV1,
+    ext_data_control_offer_v1::ExtDataControlOfferV1,
+);
+data_control!(
+    Wlr,
+    announce_wlr,
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_device_v1::ZwlrDataControlDeviceV1,
+    zwlr_data_control_source_v1::ZwlrDataControlSourceV1,
+    zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+);
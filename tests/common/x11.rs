@@ -0,0 +1,578 @@
+//! An Xvfb server and a plain X11 client copying and pasting text on it.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clip_bridge::{
+    CLIPBOARD_ATOM, ClipboardType, INCR_ATOM, MULTIPLE_ATOM, PRIMARY_ATOM, TARGETS_ATOM,
+    UTF8_STRING_ATOM,
+};
+use x11rb::CURRENT_TIME;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask,
+    GetPropertyReply, PropMode, Property, PropertyNotifyEvent, SelectionNotifyEvent,
+    SelectionRequestEvent, Timestamp, Window, WindowClass,
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+// ============================================================================
+// Xvfb
+// ============================================================================
+
+/// Environment variable making tests fail rather than skip without Xvfb.
+pub const REQUIRE_XVFB: &str = "CLIP_BRIDGE_REQUIRE_XVFB";
+
+/// An Xvfb server, killed when dropped.
+pub struct Xvfb {
+    child: Child,
+    display: String,
+}
+
+impl Xvfb {
+    /// Starts Xvfb on a free display, or returns `None` if it isn't installed.
+    ///
+    /// Panics instead if [`REQUIRE_XVFB`] is set, so that CI can't skip the
+    /// X11 tests by accident.
+    pub fn start() -> Option<Self> {
+        let Some((child, display)) = Self::spawn(None) else {
+            assert!(
+                std::env::var_os(REQUIRE_XVFB).is_none(),
+                "Xvfb not found, but {} is set",
+                REQUIRE_XVFB
+            );
+            eprintln!("Xvfb not found, skipping");
+            return None;
+        };
+        Some(Self { child, display })
+    }
+
+    /// Kills the server and starts a new one on the same display, like an
+    /// XWayland restart.
+    pub fn restart(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let (child, _) = Self::spawn(Some(&self.display)).expect("Xvfb disappeared");
+        self.child = child;
+    }
+
+    fn spawn(display: Option<&str>) -> Option<(Child, String)> {
+        let mut child = match Command::new("Xvfb")
+            .args(display)
+            .args(["-displayfd", "1", "-nolisten", "tcp"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => panic!("failed to start Xvfb: {}", e),
+        };
+
+        // Xvfb writes the display number once it accepts connections
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .expect("failed to read Xvfb display number");
+        Some((child, format!(":{}", line.trim())))
+    }
+
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    pub fn connect(&self) -> (RustConnection, usize) {
+        x11rb::connect(Some(&self.display)).expect("failed to connect to Xvfb")
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// ============================================================================
+// X11 Client
+// ============================================================================
+
+/// Largest chunk of text the [`X11Client`] writes at once, small enough for
+/// large copies to take several.
+pub const INCR_CHUNK: usize = 64 * 1024;
+
+/// An X11 client owning and converting selections on its own thread, like a
+/// regular application would.
+pub struct X11Client {
+    commands: mpsc::Sender<ClientCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+enum ClientCommand {
+    Copy(ClipboardType, String),
+    Paste(ClipboardType, mpsc::Sender<Option<String>>),
+    Convert(
+        ClipboardType,
+        String,
+        Timestamp,
+        mpsc::Sender<Option<GetPropertyReply>>,
+    ),
+    ConvertMultiple(ClipboardType, Vec<String>, mpsc::Sender<MultipleReply>),
+    Stop,
+}
+
+/// The data each target of a MULTIPLE request was converted to, or `None` for
+/// the pairs the owner rewrote to `None`. `None` altogether if it refused.
+pub type MultipleReply = Option<Vec<Option<Vec<u8>>>>;
+
+/// A conversion waiting for the owner to answer.
+enum Pending {
+    Paste(mpsc::Sender<Option<String>>),
+    Convert(mpsc::Sender<Option<GetPropertyReply>>),
+    Multiple(mpsc::Sender<MultipleReply>),
+}
+
+impl X11Client {
+    pub fn start(xvfb: &Xvfb) -> Self {
+        let (conn, screen_num) = xvfb.connect();
+        let (commands, command_rx) = mpsc::channel();
+        let thread = thread::spawn(move || ClientState::new(conn, screen_num).run(command_rx));
+        Self {
+            commands,
+            thread: Some(thread),
+        }
+    }
+
+    /// Takes ownership of `clipboard_type`, offering `text` as UTF8_STRING.
+    ///
+    /// Text larger than [`INCR_CHUNK`] is sent incrementally.
+    pub fn copy(&self, text: &str, clipboard_type: ClipboardType) {
+        self.commands
+            .send(ClientCommand::Copy(clipboard_type, text.to_string()))
+            .unwrap();
+    }
+
+    /// Converts `clipboard_type` to UTF8_STRING, following INCR transfers, or
+    /// `None` if the owner refused.
+    pub fn paste_text(&self, clipboard_type: ClipboardType) -> Option<String> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.commands
+            .send(ClientCommand::Paste(clipboard_type, reply_tx))
+            .unwrap();
+        reply_rx.recv_timeout(Duration::from_secs(1)).ok().flatten()
+    }
+
+    /// Converts `clipboard_type` to `target` as of `time`, returning the
+    /// property the owner stored the data in, or `None` if it refused.
+    pub fn convert(
+        &self,
+        clipboard_type: ClipboardType,
+        target: &str,
+        time: Timestamp,
+    ) -> Option<GetPropertyReply> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.commands
+            .send(ClientCommand::Convert(
+                clipboard_type,
+                target.to_string(),
+                time,
+                reply_tx,
+            ))
+            .unwrap();
+        reply_rx.recv_timeout(Duration::from_secs(1)).ok().flatten()
+    }
+
+    /// Converts `clipboard_type` to all of `targets` in one MULTIPLE request,
+    /// each into a property of its own.
+    pub fn convert_multiple(
+        &self,
+        clipboard_type: ClipboardType,
+        targets: &[&str],
+    ) -> MultipleReply {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let targets = targets.iter().map(|target| target.to_string()).collect();
+        self.commands
+            .send(ClientCommand::ConvertMultiple(
+                clipboard_type,
+                targets,
+                reply_tx,
+            ))
+            .unwrap();
+        reply_rx.recv_timeout(Duration::from_secs(1)).ok().flatten()
+    }
+}
+
+impl Drop for X11Client {
+    fn drop(&mut self) {
+        let _ = self.commands.send(ClientCommand::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct ClientState {
+    conn: RustConnection,
+    window: Window,
+    atoms: HashMap<&'static str, Atom>,
+    /// Text offered for each selection we own.
+    owned: HashMap<Atom, String>,
+    /// Pending conversions, keyed by selection.
+    pending: HashMap<Atom, Pending>,
+    /// The paste the owner is sending in chunks, with the data received so far.
+    incr_paste: Option<(Vec<u8>, mpsc::Sender<Option<String>>)>,
+    /// Text we send in chunks, with the offset of the next one, keyed by
+    /// requestor window and property.
+    incr_sends: HashMap<(Window, Atom), (Vec<u8>, usize)>,
+}
+
+impl ClientState {
+    const PASTE_PROPERTY: &str = "CLIP_BRIDGE_TEST_PASTE";
+    const ATOM_PAIR: &str = "ATOM_PAIR";
+
+    fn new(conn: RustConnection, screen_num: usize) -> Self {
+        let screen = &conn.setup().roots[screen_num];
+        let window = conn.generate_id().unwrap();
+        conn.create_window(
+            0,
+            window,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            0,
+            &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )
+        .unwrap();
+
+        let atoms = [
+            CLIPBOARD_ATOM,
+            PRIMARY_ATOM,
+            TARGETS_ATOM,
+            UTF8_STRING_ATOM,
+            INCR_ATOM,
+            Self::PASTE_PROPERTY,
+            MULTIPLE_ATOM,
+            Self::ATOM_PAIR,
+        ]
+        .into_iter()
+        .map(|name| {
+            let atom = conn
+                .intern_atom(false, name.as_bytes())
+                .unwrap()
+                .reply()
+                .unwrap()
+                .atom;
+            (name, atom)
+        })
+        .collect();
+
+        Self {
+            conn,
+            window,
+            atoms,
+            owned: HashMap::new(),
+            pending: HashMap::new(),
+            incr_paste: None,
+            incr_sends: HashMap::new(),
+        }
+    }
+
+    fn selection_atom(&self, clipboard_type: &ClipboardType) -> Atom {
+        match clipboard_type {
+            ClipboardType::Clipboard => self.atoms[CLIPBOARD_ATOM],
+            ClipboardType::Primary => self.atoms[PRIMARY_ATOM],
+        }
+    }
+
+    fn run(mut self, commands: mpsc::Receiver<ClientCommand>) {
+        loop {
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    ClientCommand::Copy(clipboard_type, text) => {
+                        let selection = self.selection_atom(&clipboard_type);
+                        self.owned.insert(selection, text);
+                        self.conn
+                            .set_selection_owner(self.window, selection, CURRENT_TIME)
+                            .unwrap();
+                    }
+                    ClientCommand::Paste(clipboard_type, reply) => {
+                        let selection = self.selection_atom(&clipboard_type);
+                        self.conn
+                            .convert_selection(
+                                self.window,
+                                selection,
+                                self.atoms[UTF8_STRING_ATOM],
+                                self.atoms[Self::PASTE_PROPERTY],
+                                CURRENT_TIME,
+                            )
+                            .unwrap();
+                        self.pending.insert(selection, Pending::Paste(reply));
+                    }
+                    ClientCommand::Convert(clipboard_type, target, time, reply) => {
+                        let selection = self.selection_atom(&clipboard_type);
+                        self.conn
+                            .convert_selection(
+                                self.window,
+                                selection,
+                                self.intern(&target),
+                                self.atoms[Self::PASTE_PROPERTY],
+                                time,
+                            )
+                            .unwrap();
+                        self.pending.insert(selection, Pending::Convert(reply));
+                    }
+                    ClientCommand::ConvertMultiple(clipboard_type, targets, reply) => {
+                        let selection = self.selection_atom(&clipboard_type);
+                        self.convert_multiple(selection, &targets);
+                        self.pending.insert(selection, Pending::Multiple(reply));
+                    }
+                    ClientCommand::Stop => return,
+                }
+            }
+            self.conn.flush().unwrap();
+
+            while let Some(event) = self.conn.poll_for_event().unwrap() {
+                match event {
+                    Event::SelectionRequest(event) => self.answer(event),
+                    Event::SelectionNotify(event) => self.receive(event),
+                    Event::PropertyNotify(event) => self.property_changed(event),
+                    Event::SelectionClear(event) => {
+                        self.owned.remove(&event.selection);
+                    }
+                    _ => {}
+                }
+            }
+            self.conn.flush().unwrap();
+
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Answers TARGETS and UTF8_STRING requests for a selection we own.
+    fn answer(&mut self, event: SelectionRequestEvent) {
+        let text = self.owned.get(&event.selection);
+        let property = if text.is_some() && event.target == self.atoms[TARGETS_ATOM] {
+            let targets = [self.atoms[TARGETS_ATOM], self.atoms[UTF8_STRING_ATOM]];
+            self.conn
+                .change_property32(
+                    PropMode::REPLACE,
+                    event.requestor,
+                    event.property,
+                    AtomEnum::ATOM,
+                    &targets,
+                )
+                .unwrap();
+            event.property
+        } else if let Some(text) = text.filter(|_| event.target == self.atoms[UTF8_STRING_ATOM]) {
+            if text.len() > INCR_CHUNK {
+                // Announce the size, then wait for the requestor to delete the
+                // property before each chunk
+                self.conn
+                    .change_window_attributes(
+                        event.requestor,
+                        &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+                    )
+                    .unwrap();
+                self.conn
+                    .change_property32(
+                        PropMode::REPLACE,
+                        event.requestor,
+                        event.property,
+                        self.atoms[INCR_ATOM],
+                        &[text.len() as u32],
+                    )
+                    .unwrap();
+                self.incr_sends.insert(
+                    (event.requestor, event.property),
+                    (text.clone().into_bytes(), 0),
+                );
+            } else {
+                self.conn
+                    .change_property8(
+                        PropMode::REPLACE,
+                        event.requestor,
+                        event.property,
+                        event.target,
+                        text.as_bytes(),
+                    )
+                    .unwrap();
+            }
+            event.property
+        } else {
+            AtomEnum::NONE.into()
+        };
+
+        let notify = SelectionNotifyEvent {
+            response_type: x11rb::protocol::xproto::SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: event.time,
+            requestor: event.requestor,
+            selection: event.selection,
+            target: event.target,
+            property,
+        };
+        self.conn
+            .send_event(false, event.requestor, EventMask::NO_EVENT, notify)
+            .unwrap();
+    }
+
+    /// Lists each of `targets` with a property of its own on our window, and
+    /// asks the owner of `selection` to convert them all.
+    fn convert_multiple(&self, selection: Atom, targets: &[String]) {
+        let pairs = targets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, target)| {
+                let property = format!("{}_{}", Self::PASTE_PROPERTY, i);
+                [self.intern(target), self.intern(&property)]
+            })
+            .collect::<Vec<_>>();
+        self.conn
+            .change_property32(
+                PropMode::REPLACE,
+                self.window,
+                self.atoms[Self::PASTE_PROPERTY],
+                self.atoms[Self::ATOM_PAIR],
+                &pairs,
+            )
+            .unwrap();
+        self.conn
+            .convert_selection(
+                self.window,
+                selection,
+                self.atoms[MULTIPLE_ATOM],
+                self.atoms[Self::PASTE_PROPERTY],
+                CURRENT_TIME,
+            )
+            .unwrap();
+    }
+
+    /// Completes a conversion once the owner answered.
+    fn receive(&mut self, event: SelectionNotifyEvent) {
+        let reply = match self.pending.remove(&event.selection) {
+            Some(Pending::Paste(reply)) => reply,
+            Some(Pending::Convert(reply)) => {
+                let _ = reply.send(
+                    (event.property != u32::from(AtomEnum::NONE))
+                        .then(|| self.take_property(event.property))
+                        .flatten(),
+                );
+                return;
+            }
+            Some(Pending::Multiple(reply)) => {
+                let _ = reply.send(self.receive_multiple(event.property));
+                return;
+            }
+            None => return,
+        };
+        let property = (event.property != u32::from(AtomEnum::NONE))
+            .then(|| self.take_property(event.property))
+            .flatten();
+        match property {
+            // Deleting the INCR property asked the owner for the first chunk
+            Some(property) if property.type_ == self.atoms[INCR_ATOM] => {
+                self.incr_paste = Some((Vec::new(), reply));
+            }
+            property => {
+                let _ = reply.send(property.and_then(|p| String::from_utf8(p.value).ok()));
+            }
+        }
+    }
+
+    /// Sends the next chunk of an INCR copy once the requestor deleted the
+    /// previous one, or reads the next chunk of an INCR paste.
+    fn property_changed(&mut self, event: PropertyNotifyEvent) {
+        if event.state == Property::DELETE {
+            self.send_next_chunk(event.window, event.atom);
+            return;
+        }
+        if self.incr_paste.is_none()
+            || event.window != self.window
+            || event.atom != self.atoms[Self::PASTE_PROPERTY]
+            || event.state != Property::NEW_VALUE
+        {
+            return;
+        }
+        let Some(chunk) = self.take_property(event.atom) else {
+            return;
+        };
+        let Some((data, _)) = &mut self.incr_paste else {
+            return;
+        };
+        if !chunk.value.is_empty() {
+            data.extend_from_slice(&chunk.value);
+            return;
+        }
+        let (data, reply) = self.incr_paste.take().unwrap();
+        let _ = reply.send(String::from_utf8(data).ok());
+    }
+
+    /// Writes the next chunk of the text sent to `property` of `requestor`,
+    /// ending with an empty one.
+    fn send_next_chunk(&mut self, requestor: Window, property: Atom) {
+        let Some((data, offset)) = self.incr_sends.get_mut(&(requestor, property)) else {
+            return;
+        };
+        let end = (*offset + INCR_CHUNK).min(data.len());
+        self.conn
+            .change_property8(
+                PropMode::REPLACE,
+                requestor,
+                property,
+                self.atoms[UTF8_STRING_ATOM],
+                &data[*offset..end],
+            )
+            .unwrap();
+        let finished = *offset == end;
+        *offset = end;
+        if finished {
+            self.incr_sends.remove(&(requestor, property));
+        }
+    }
+
+    /// Reads the data of each pair the owner answered a MULTIPLE request with.
+    fn receive_multiple(&self, property: Atom) -> MultipleReply {
+        if property == u32::from(AtomEnum::NONE) {
+            return None;
+        }
+        let pairs = self.take_property(property)?.value32()?.collect::<Vec<_>>();
+        let converted = pairs
+            .chunks_exact(2)
+            .map(|pair| {
+                (pair[1] != u32::from(AtomEnum::NONE))
+                    .then(|| self.take_property(pair[1]))
+                    .flatten()
+                    .map(|property| property.value)
+            })
+            .collect();
+        Some(converted)
+    }
+
+    fn intern(&self, name: &str) -> Atom {
+        self.conn
+            .intern_atom(false, name.as_bytes())
+            .unwrap()
+            .reply()
+            .unwrap()
+            .atom
+    }
+
+    /// Reads and deletes `property` on our window.
+    fn take_property(&self, property: Atom) -> Option<GetPropertyReply> {
+        self.conn
+            .get_property(true, self.window, property, AtomEnum::ANY, 0, u32::MAX / 4)
+            .ok()?
+            .reply()
+            .ok()
+    }
+}
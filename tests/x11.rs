@@ -0,0 +1,59 @@
+//! Tests of the X11 backend on its own, against a headless X server.
+
+mod common;
+
+use clip_bridge::x11::X11State;
+use clip_bridge::{
+    CLIPBOARD_ATOM, INCR_ATOM, MULTIPLE_ATOM, PRIMARY_ATOM, STRING_ATOM, TARGETS_ATOM, TEXT_ATOM,
+    TEXT_PLAIN_ATOM, TEXT_PLAIN_UTF8_ATOM, UTF8_STRING_ATOM,
+};
+use tokio::sync::mpsc::unbounded_channel;
+
+use common::x11::Xvfb;
+
+/// Creates the state of an X11 backend connected to `xvfb`.
+fn x11_state(xvfb: &Xvfb) -> X11State {
+    let (conn, screen_num) = xvfb.connect();
+    let (sync_tx, _sync_rx) = unbounded_channel();
+    let (_set_clipboard_tx, set_clipboard_rx) = unbounded_channel();
+    X11State::new(conn, screen_num, sync_tx, set_clipboard_rx)
+        .expect("Failed to initialize X11State")
+}
+
+#[test]
+fn test_x11_state_initialization() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    x11_state(&xvfb);
+}
+
+#[test]
+fn test_atom_interning() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let x11_state = x11_state(&xvfb);
+
+    // Test that all required atoms are interned
+    let required_atoms = vec![
+        CLIPBOARD_ATOM,
+        PRIMARY_ATOM,
+        TARGETS_ATOM,
+        MULTIPLE_ATOM,
+        INCR_ATOM,
+        UTF8_STRING_ATOM,
+        TEXT_ATOM,
+        STRING_ATOM,
+        TEXT_PLAIN_UTF8_ATOM,
+        TEXT_PLAIN_ATOM,
+    ];
+
+    for atom_name in required_atoms {
+        assert!(
+            x11_state.get_atom(atom_name).is_some(),
+            "Atom {} not interned",
+            atom_name
+        );
+    }
+}